@@ -0,0 +1,44 @@
+//! Sequential against parallel batch sealing, to find the threshold where the thread pool pays off:
+//!
+//!     cargo bench -p colloid --bench batch
+
+use colloid::CipherFunction;
+use colloid::aead::bytes::BytesMut;
+use colloid::batch::{Batch, Record};
+use colloid::cipher::Cipher;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+// A typical datagram payload.
+const PAYLOAD: usize = 1200;
+
+fn seal(c: &mut Criterion) {
+    let cipher = Cipher::new([7u8; 32].into());
+    let mut group = c.benchmark_group("seal");
+    for count in [1, 4, 16, 64, 256, 1024] {
+        group.throughput(Throughput::Bytes((count * PAYLOAD) as u64));
+        for (name, batch) in [
+            ("sequential", Batch::with_threshold(usize::MAX)),
+            ("parallel", Batch::with_threshold(0)),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, count), &count, |b, &count| {
+                b.iter_batched_ref(
+                    || {
+                        (0..count as u64)
+                            .map(|n| {
+                                let mut buffer = BytesMut::with_capacity(PAYLOAD + Cipher::TAGLEN);
+                                buffer.resize(PAYLOAD, 0);
+                                Record::new(n, &[], buffer)
+                            })
+                            .collect::<Vec<_>>()
+                    },
+                    |records| batch.seal(&cipher, records).unwrap(),
+                    criterion::BatchSize::LargeInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, seal);
+criterion_main!(benches);
@@ -1,6 +1,7 @@
 use colloid::{
-    dh::DHLEN,
-    hash::{HASHLEN, once},
+    DhFunction, HashFunction,
+    dh::X25519,
+    hash::{Blake3, HASHLEN, once},
 };
 
 fn main() {
@@ -16,16 +17,16 @@ fn main() {
     println!("Hmac result: {}", hmac);
     // Expected:
     //      Hmac result: 5306de6f781feb39730ee7c1d7e62ce29e0d261c94ef2a379eab76fbc976500c
-    let input_key_material = &[0u8; DHLEN];
-    let buf1: &mut [u8; 32] = &mut [0u8; 32];
-    let buf2: &mut [u8; 32] = &mut [0u8; 32];
-    let buf3: &mut [u8; 32] = &mut [0u8; 32];
-    once::rayon::hkdf(key, input_key_material, 2, buf1, buf2, buf3);
-    println!("HKDF result 1: {:?}", buf1);
-    println!("HKDF result 2: {:?}", buf2);
-    println!("HKDF result 3: {:?}", buf3); // Should be empty.
-    once::rayon::hkdf(key, input_key_material, 3, buf1, buf2, buf3);
-    println!("HKDF result 1: {:?}", buf1);
-    println!("HKDF result 2: {:?}", buf2);
-    println!("HKDF result 3: {:?}", buf3);
+    let input_key_material = &[0u8; X25519::DHLEN];
+    let [out1, out2] = Blake3::hkdf(key, input_key_material);
+    println!("HKDF result 1: {:?}", out1);
+    println!("HKDF result 2: {:?}", out2);
+    let [out1, out2, out3] = Blake3::hkdf(key, input_key_material);
+    println!("HKDF result 1: {:?}", out1);
+    println!("HKDF result 2: {:?}", out2);
+    println!("HKDF result 3: {:?}", out3);
+    // Exporting key material of any length.
+    let mut okm = [0u8; 100];
+    Blake3::hkdf_expand(key, input_key_material, b"example", &mut okm).unwrap();
+    println!("HKDF expand result: {:?}", okm);
 }
@@ -0,0 +1,138 @@
+//! Sealing and opening many independent records at once, spread over the rayon thread pool.
+//!
+//! Every record has its own nonce and associated data, like the packets of a bulk transfer. Below
+//! the threshold (in payload bytes for the whole batch) the records are processed on the calling
+//! thread, since handing small batches to the thread pool costs more than it saves. The
+//! `batch` benchmark shows where the crossover is on a given machine.
+
+use aead::bytes::BytesMut;
+use rayon::prelude::*;
+
+use crate::CipherFunction;
+
+/// A record of a batch, encrypted or decrypted in place.
+#[derive(Debug)]
+pub struct Record<'a, B: aead::Buffer = BytesMut> {
+    pub n: u64,
+    pub ad: &'a [u8],
+    pub buffer: B,
+}
+
+impl<'a, B: aead::Buffer> Record<'a, B> {
+    pub fn new(n: u64, ad: &'a [u8], buffer: B) -> Self {
+        Self { n, ad, buffer }
+    }
+}
+
+/// Batch settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Batch {
+    threshold: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            threshold: Self::DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl Batch {
+    /// Batches with less payload than this stay single-threaded by default.
+    pub const DEFAULT_THRESHOLD: usize = 64 * 1024;
+
+    /// `threshold` is the total payload size in bytes from which a batch goes parallel, `0`
+    /// always and `usize::MAX` never uses the thread pool.
+    pub fn with_threshold(threshold: usize) -> Self {
+        Self { threshold }
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn is_parallel<B: aead::Buffer>(&self, records: &[Record<'_, B>]) -> bool {
+        records.len() > 1
+            && records
+                .iter()
+                .map(|record| record.buffer.len())
+                .sum::<usize>()
+                >= self.threshold
+    }
+
+    /// Encrypts every record in place, appending its tag. Fails only if a buffer cannot grow.
+    pub fn seal<C, B>(&self, cipher: &C, records: &mut [Record<'_, B>]) -> Result<(), aead::Error>
+    where
+        C: CipherFunction + Sync,
+        B: aead::Buffer + Send,
+    {
+        let seal = |record: &mut Record<'_, B>| {
+            cipher.encrypt_in_place(record.n, record.ad, &mut record.buffer)
+        };
+        if self.is_parallel(records) {
+            records.par_iter_mut().try_for_each(seal)
+        } else {
+            records.iter_mut().try_for_each(seal)
+        }
+    }
+
+    /// Decrypts every record in place, stripping its tag. Records are independent, so the result
+    /// of each one is returned in order, a record which fails is left as it was.
+    pub fn open<C, B>(
+        &self,
+        cipher: &C,
+        records: &mut [Record<'_, B>],
+    ) -> Vec<Result<(), aead::Error>>
+    where
+        C: CipherFunction + Sync,
+        B: aead::Buffer + Send,
+    {
+        let open = |record: &mut Record<'_, B>| {
+            cipher.decrypt_in_place(record.n, record.ad, &mut record.buffer)
+        };
+        if self.is_parallel(records) {
+            records.par_iter_mut().map(open).collect()
+        } else {
+            records.iter_mut().map(open).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::Cipher;
+
+    fn records(ad: &[u8]) -> Vec<Record<'_>> {
+        (0..32)
+            .map(|n| Record::new(n, ad, BytesMut::from(&[n as u8; 1200][..])))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let cipher = Cipher::new([7u8; 32].into());
+        let mut sequential = records(b"ad");
+        let mut parallel = records(b"ad");
+        Batch::with_threshold(usize::MAX)
+            .seal(&cipher, &mut sequential)
+            .unwrap();
+        Batch::with_threshold(0)
+            .seal(&cipher, &mut parallel)
+            .unwrap();
+        for (n, (a, b)) in sequential.iter().zip(&parallel).enumerate() {
+            assert_eq!(a.buffer, b.buffer);
+            assert_eq!(a.buffer, cipher.encrypt(n as u64, b"ad", &[n as u8; 1200]));
+        }
+
+        // Swapping two nonces makes exactly those records fail.
+        parallel[3].n = 4;
+        parallel[4].n = 3;
+        let results = Batch::with_threshold(0).open(&cipher, &mut parallel);
+        for (n, result) in results.iter().enumerate() {
+            assert_eq!(result.is_ok(), n != 3 && n != 4);
+        }
+        assert_eq!(parallel[5].buffer, vec![5u8; 1200]);
+    }
+}
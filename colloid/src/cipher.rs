@@ -1,23 +1,315 @@
+//! The cipher mod is based on The Noise Protocol spec: https://noiseprotocol.org/noise.html#cipher-functions
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
 use chacha12_blake3::ChaCha12Blake3;
 
+use crate::{ByteArray, SecretKey};
+
+/// Cipher functions as described in the Noise spec. `n` is the 8-byte nonce of a CipherState, each
+/// implementation decides how it is encoded into the nonce of the underlying AEAD.
+///
+/// Implementations only provide the in-place functions with a detached tag, the allocating
+/// `encrypt`/`decrypt` and the [`aead::Buffer`] (e.g. `BytesMut`) variants are built on them.
+pub trait CipherFunction: Sized {
+    /// Name used in Noise protocol names, e.g. `ChaChaPoly`.
+    const NAME: &'static str;
+    /// Length of the authentication tag appended to every ciphertext.
+    const TAGLEN: usize;
+
+    /// A `TAGLEN`-byte authentication tag.
+    type Tag: ByteArray;
+
+    fn new(k: SecretKey) -> Self;
+
+    /// Encrypts `buffer` in place and returns the tag.
+    fn encrypt_in_place_detached(&self, n: u64, ad: &[u8], buffer: &mut [u8]) -> Self::Tag;
+
+    /// Checks `tag` and decrypts `buffer` in place. `buffer` is left untouched if the tag is wrong.
+    fn decrypt_in_place_detached(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), aead::Error>;
+
+    /// Encrypts the contents of `buffer` in place and appends the tag.
+    fn encrypt_in_place(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut dyn aead::Buffer,
+    ) -> Result<(), aead::Error> {
+        let tag = self.encrypt_in_place_detached(n, ad, buffer.as_mut());
+        buffer.extend_from_slice(tag.as_ref())
+    }
+
+    /// Decrypts the contents of `buffer` in place and strips the tag.
+    fn decrypt_in_place(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut dyn aead::Buffer,
+    ) -> Result<(), aead::Error> {
+        let len = buffer.len().checked_sub(Self::TAGLEN).ok_or(aead::Error)?;
+        let (message, tag) = buffer.as_mut().split_at_mut(len);
+        self.decrypt_in_place_detached(n, ad, message, tag)?;
+        buffer.truncate(len);
+        Ok(())
+    }
+
+    /// ENCRYPT(k, n, ad, plaintext)
+    fn encrypt(&self, n: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + Self::TAGLEN);
+        ciphertext.extend_from_slice(plaintext);
+        let tag = self.encrypt_in_place_detached(n, ad, &mut ciphertext);
+        ciphertext.extend_from_slice(tag.as_ref());
+        ciphertext
+    }
+
+    /// DECRYPT(k, n, ad, ciphertext)
+    fn decrypt(&self, n: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, aead::Error> {
+        let mut plaintext = ciphertext.to_vec();
+        self.decrypt_in_place(n, ad, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    /// REKEY(k): replaces the key with the first 32 bytes of `ENCRYPT(k, maxnonce, zerolen, zeros)`.
+    fn rekey(&mut self) {
+        let mut k = SecretKey::new([0u8; 32]);
+        self.encrypt_in_place_detached(u64::MAX, &[], k.expose_mut());
+        *self = Self::new(k);
+    }
+}
+
+/// The default cipher of colloid, ChaCha12-BLAKE3 with a 32-byte tag. Only available with `std`.
+#[cfg(feature = "std")]
 pub struct Cipher {
     pub inner: ChaCha12Blake3,
 }
 
+#[cfg(feature = "std")]
 impl Cipher {
-    pub fn new(k: [u8; 32]) -> Self {
-        Self { inner: ChaCha12Blake3::new(k) }
+    // The 64-bit counter is encoded little-endian into the first 8 bytes of the 32-byte nonce.
+    fn nonce(n: u64) -> [u8; 32] {
+        let mut nonce = [0u8; 32];
+        nonce[..8].copy_from_slice(&n.to_le_bytes());
+        nonce
+    }
+}
+
+#[cfg(feature = "std")]
+impl CipherFunction for Cipher {
+    const NAME: &'static str = "ChaCha12Blake3";
+    const TAGLEN: usize = chacha12_blake3::TAG_SIZE;
+
+    type Tag = [u8; chacha12_blake3::TAG_SIZE];
+
+    fn new(k: SecretKey) -> Self {
+        Self {
+            inner: ChaCha12Blake3::new(*k.expose()),
+        }
+    }
+
+    fn encrypt_in_place_detached(&self, n: u64, ad: &[u8], buffer: &mut [u8]) -> Self::Tag {
+        self.inner
+            .encrypt_in_place_detached(&Self::nonce(n), buffer, ad)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), aead::Error> {
+        let tag = tag.try_into().map_err(|_| aead::Error)?;
+        self.inner
+            .decrypt_in_place_detached(&Self::nonce(n), buffer, tag, ad)
+            .map_err(|_| aead::Error)
+    }
+}
+
+/// ChaCha20-Poly1305 as specified by the Noise spec, `n` is encoded as 32 bits of zeros followed
+/// by little-endian encoding of n.
+#[cfg(feature = "chachapoly")]
+pub struct ChaChaPoly {
+    pub inner: chacha20poly1305::ChaCha20Poly1305,
+}
+
+#[cfg(feature = "chachapoly")]
+impl ChaChaPoly {
+    fn nonce(n: u64) -> chacha20poly1305::Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&n.to_le_bytes());
+        nonce.into()
     }
+}
+
+#[cfg(feature = "chachapoly")]
+impl CipherFunction for ChaChaPoly {
+    const NAME: &'static str = "ChaChaPoly";
+    const TAGLEN: usize = 16;
+
+    type Tag = [u8; 16];
+
+    fn new(k: SecretKey) -> Self {
+        use chacha20poly1305::KeyInit;
+        Self {
+            inner: chacha20poly1305::ChaCha20Poly1305::new(k.expose().into()),
+        }
+    }
+
+    fn encrypt_in_place_detached(&self, n: u64, ad: &[u8], buffer: &mut [u8]) -> Self::Tag {
+        use aead::AeadInPlace;
+        self.inner
+            .encrypt_in_place_detached(&Self::nonce(n), ad, buffer)
+            .expect("plaintext exceeds the ChaCha20-Poly1305 limit")
+            .into()
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), aead::Error> {
+        use aead::AeadInPlace;
+        let tag: [u8; 16] = tag.try_into().map_err(|_| aead::Error)?;
+        self.inner
+            .decrypt_in_place_detached(&Self::nonce(n), ad, buffer, &tag.into())
+    }
+}
+
+/// AES-256-GCM as specified by the Noise spec, `n` is encoded as 32 bits of zeros followed by
+/// big-endian encoding of n.
+#[cfg(feature = "aesgcm")]
+pub struct AesGcm {
+    pub inner: aes_gcm::Aes256Gcm,
+}
+
+#[cfg(feature = "aesgcm")]
+impl AesGcm {
+    fn nonce(n: u64) -> aes_gcm::Nonce<aes_gcm::aead::consts::U12> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&n.to_be_bytes());
+        nonce.into()
+    }
+}
+
+#[cfg(feature = "aesgcm")]
+impl CipherFunction for AesGcm {
+    const NAME: &'static str = "AESGCM";
+    const TAGLEN: usize = 16;
+
+    type Tag = [u8; 16];
+
+    fn new(k: SecretKey) -> Self {
+        use aes_gcm::KeyInit;
+        Self {
+            inner: aes_gcm::Aes256Gcm::new(k.expose().into()),
+        }
+    }
+
+    fn encrypt_in_place_detached(&self, n: u64, ad: &[u8], buffer: &mut [u8]) -> Self::Tag {
+        use aead::AeadInPlace;
+        self.inner
+            .encrypt_in_place_detached(&Self::nonce(n), ad, buffer)
+            .expect("plaintext exceeds the AES-GCM limit")
+            .into()
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        n: u64,
+        ad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), aead::Error> {
+        use aead::AeadInPlace;
+        let tag: [u8; 16] = tag.try_into().map_err(|_| aead::Error)?;
+        self.inner
+            .decrypt_in_place_detached(&Self::nonce(n), ad, buffer, &tag.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new([7u8; 32].into());
+        let ciphertext = cipher.encrypt(1, b"ad", b"hello");
+        assert_eq!(ciphertext.len(), 5 + Cipher::TAGLEN);
+        assert_eq!(cipher.decrypt(1, b"ad", &ciphertext).unwrap(), b"hello");
+        assert!(cipher.decrypt(2, b"ad", &ciphertext).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn rekey_changes_key() {
+        let mut cipher = Cipher::new([7u8; 32].into());
+        let ciphertext = cipher.encrypt(0, &[], b"hello");
+        cipher.rekey();
+        assert!(cipher.decrypt(0, &[], &ciphertext).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn in_place_round_trip() {
+        use aead::bytes::BytesMut;
+        let cipher = Cipher::new([7u8; 32].into());
+        let expected = cipher.encrypt(3, b"header", b"payload");
+
+        let mut packet = BytesMut::with_capacity(64);
+        packet.extend_from_slice(b"payload");
+        let capacity = packet.capacity();
+        cipher.encrypt_in_place(3, b"header", &mut packet).unwrap();
+        assert_eq!(&packet[..], &expected[..]);
+        cipher.decrypt_in_place(3, b"header", &mut packet).unwrap();
+        assert_eq!(&packet[..], b"payload");
+        assert_eq!(packet.capacity(), capacity);
+
+        let mut buffer = *b"payload";
+        let tag = cipher.encrypt_in_place_detached(3, b"header", &mut buffer);
+        assert!(
+            cipher
+                .decrypt_in_place_detached(3, b"other", &mut buffer, &tag)
+                .is_err()
+        );
+        cipher
+            .decrypt_in_place_detached(3, b"header", &mut buffer, &tag)
+            .unwrap();
+        assert_eq!(&buffer, b"payload");
 
-    pub fn encrypt(&self, nonce: [u8; 32], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
-        self.inner.encrypt(&nonce, plaintext, ad) 
+        let mut short = BytesMut::from(&[0u8; 4][..]);
+        assert!(cipher.decrypt_in_place(0, &[], &mut short).is_err());
     }
 
-    pub fn decrypt(&self, nonce: [u8; 32], ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, chacha12_blake3::Error> {
-        self.inner.decrypt(&nonce, ciphertext, ad)
+    #[cfg(feature = "chachapoly")]
+    #[test]
+    fn chachapoly_nonce_layout() {
+        use aead::Aead;
+        let cipher = ChaChaPoly::new([0x80u8; 32].into());
+        let nonce = [0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0];
+        let expected = cipher.inner.encrypt(&nonce.into(), &b"hello"[..]).unwrap();
+        assert_eq!(cipher.encrypt(0x0201, &[], b"hello"), expected);
+        assert_eq!(cipher.decrypt(0x0201, &[], &expected).unwrap(), b"hello");
     }
 
-    pub fn rekey(&mut self, k: [u8; 32]) {
-        self.inner = Cipher::new(k).inner;
+    #[cfg(feature = "aesgcm")]
+    #[test]
+    fn aesgcm_nonce_layout() {
+        use aead::Aead;
+        let cipher = AesGcm::new([0x80u8; 32].into());
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1];
+        let expected = cipher.inner.encrypt(&nonce.into(), &b"hello"[..]).unwrap();
+        assert_eq!(cipher.encrypt(0x0201, &[], b"hello"), expected);
+        assert_eq!(cipher.decrypt(0x0201, &[], &expected).unwrap(), b"hello");
     }
 }
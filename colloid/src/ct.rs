@@ -0,0 +1,28 @@
+//! Constant-time comparisons, for keys, MACs and other values derived from secrets.
+
+use subtle::ConstantTimeEq;
+
+/// Compares two byte strings in constant time. Only the lengths are compared in variable time.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Checks in constant time whether every byte of `a` is zero.
+pub fn is_zero(a: &[u8]) -> bool {
+    a.iter().fold(0u8, |acc, byte| acc | byte).ct_eq(&0).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons() {
+        assert!(eq(b"mac", b"mac"));
+        assert!(!eq(b"mac", b"mad"));
+        assert!(!eq(b"mac", b"ma"));
+        assert!(is_zero(&[0u8; 32]));
+        assert!(is_zero(&[]));
+        assert!(!is_zero(&[0, 0, 1]));
+    }
+}
@@ -1,8 +1,155 @@
 //! The DH mod is based on The Noise Protocol spec: https://noiseprotocol.org/noise.html#dh-functions
 
-/// The default DH length for x25519 ecdh
-pub const DHLEN: usize = 32;
+use rand_core::{CryptoRng, RngCore};
 
+use crate::Error;
+
+/// DH functions as described in the Noise spec.
+pub trait DhFunction {
+    /// Name used in Noise protocol names, e.g. `25519`.
+    const NAME: &'static str;
+    /// Length in bytes of public keys and DH outputs, this is also the size of the input key
+    /// material the handshake feeds into HKDF.
+    const DHLEN: usize;
+
+    type PrivateKey;
+    type PublicKey: AsRef<[u8]> + Clone;
+    type SharedSecret: AsRef<[u8]>;
+
+    /// GENERATE_KEYPAIR(), with the keys drawn from `rng`. A seeded RNG gives reproducible keys
+    /// for tests and test vectors.
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+    ) -> (Self::PrivateKey, Self::PublicKey);
+
+    /// GENERATE_KEYPAIR(), with the keys drawn from the OS RNG.
+    #[cfg(feature = "getrandom")]
+    fn generate_keypair() -> (Self::PrivateKey, Self::PublicKey) {
+        Self::generate_keypair_with_rng(&mut rand_core::OsRng)
+    }
+
+    fn public_key(private_key: &Self::PrivateKey) -> Self::PublicKey;
+
+    /// The raw bytes of a private key, as stored in key files.
+    fn private_key_bytes(private_key: &Self::PrivateKey) -> &[u8];
+
+    /// Parses a private key from its raw bytes, returns `None` if the length is wrong.
+    fn private_key_from_bytes(bytes: &[u8]) -> Option<Self::PrivateKey>;
+
+    /// Parses a `DHLEN`-byte public key, returns `None` if the length is wrong.
+    fn public_key_from_bytes(bytes: &[u8]) -> Option<Self::PublicKey>;
+
+    /// DH(key_pair, public_key)
+    ///
+    /// Low-order public keys are not rejected, they give an all-zero output. Use
+    /// [`DhFunction::dh_checked`] for public keys received from a peer.
+    fn dh(private_key: &Self::PrivateKey, public_key: &Self::PublicKey) -> Self::SharedSecret;
+
+    /// DH(key_pair, public_key), returning [`Error::NonContributoryDh`] if the output is all
+    /// zero, which is what a malicious peer sending a low-order public key produces.
+    fn dh_checked(
+        private_key: &Self::PrivateKey,
+        public_key: &Self::PublicKey,
+    ) -> Result<Self::SharedSecret, Error> {
+        let shared_secret = Self::dh(private_key, public_key);
+        if crate::ct::is_zero(shared_secret.as_ref()) {
+            return Err(Error::NonContributoryDh);
+        }
+        Ok(shared_secret)
+    }
+}
+
+/// Curve25519 DH, "25519" in Noise protocol names.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct X25519;
+
+impl DhFunction for X25519 {
+    const NAME: &'static str = "25519";
+    const DHLEN: usize = 32;
+
+    // A StaticSecret is used since a Noise keypair may take part in several DH operations.
+    type PrivateKey = x25519_dalek::StaticSecret;
+    type PublicKey = x25519_dalek::PublicKey;
+    type SharedSecret = x25519_dalek::SharedSecret;
+
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+    ) -> (Self::PrivateKey, Self::PublicKey) {
+        let private_key = x25519_dalek::StaticSecret::random_from_rng(rng);
+        let public_key = Self::public_key(&private_key);
+        (private_key, public_key)
+    }
+
+    fn public_key(private_key: &Self::PrivateKey) -> Self::PublicKey {
+        x25519_dalek::PublicKey::from(private_key)
+    }
+
+    fn private_key_bytes(private_key: &Self::PrivateKey) -> &[u8] {
+        private_key.as_bytes()
+    }
+
+    fn private_key_from_bytes(bytes: &[u8]) -> Option<Self::PrivateKey> {
+        let bytes: [u8; 32] = bytes.try_into().ok()?;
+        Some(x25519_dalek::StaticSecret::from(bytes))
+    }
+
+    fn public_key_from_bytes(bytes: &[u8]) -> Option<Self::PublicKey> {
+        let bytes: [u8; 32] = bytes.try_into().ok()?;
+        Some(x25519_dalek::PublicKey::from(bytes))
+    }
+
+    fn dh(private_key: &Self::PrivateKey, public_key: &Self::PublicKey) -> Self::SharedSecret {
+        private_key.diffie_hellman(public_key)
+    }
+}
+
+/// Curve448 DH, "448" in Noise protocol names.
+#[cfg(feature = "x448")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct X448;
+
+#[cfg(feature = "x448")]
+impl DhFunction for X448 {
+    const NAME: &'static str = "448";
+    const DHLEN: usize = 56;
+
+    // x448::Secret is never wiped, so the (unclamped) scalar bytes are kept instead.
+    type PrivateKey = crate::Secret<[u8; 56]>;
+    type PublicKey = [u8; 56];
+    type SharedSecret = crate::Secret<[u8; 56]>;
+
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+    ) -> (Self::PrivateKey, Self::PublicKey) {
+        let mut private_key = crate::Secret::new([0u8; 56]);
+        rng.fill_bytes(private_key.expose_mut());
+        let public_key = Self::public_key(&private_key);
+        (private_key, public_key)
+    }
+
+    fn public_key(private_key: &Self::PrivateKey) -> Self::PublicKey {
+        x448::x448_unchecked(*private_key.expose(), x448::X448_BASEPOINT_BYTES)
+    }
+
+    fn private_key_bytes(private_key: &Self::PrivateKey) -> &[u8] {
+        private_key.expose()
+    }
+
+    fn private_key_from_bytes(bytes: &[u8]) -> Option<Self::PrivateKey> {
+        Some(crate::Secret::new(bytes.try_into().ok()?))
+    }
+
+    fn public_key_from_bytes(bytes: &[u8]) -> Option<Self::PublicKey> {
+        bytes.try_into().ok()
+    }
+
+    // Low order points are not rejected here, like the 25519 implementation, see `dh_checked`.
+    fn dh(private_key: &Self::PrivateKey, public_key: &Self::PublicKey) -> Self::SharedSecret {
+        crate::Secret::new(x448::x448_unchecked(*private_key.expose(), *public_key))
+    }
+}
+
+#[cfg(feature = "getrandom")]
 pub mod ephemeral_key {
     use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
     pub fn generate_keypair() -> (EphemeralSecret, PublicKey) {
@@ -18,6 +165,7 @@ pub mod ephemeral_key {
 }
 
 // Used to generate 's'
+#[cfg(feature = "getrandom")]
 pub mod static_key {
     use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
     pub fn generate_keypair() -> (StaticSecret, PublicKey) {
@@ -32,6 +180,7 @@ pub mod static_key {
 }
 
 // Suitable for The Noise Protocol.
+#[cfg(feature = "getrandom")]
 pub mod reusable_key {
     use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret};
     pub fn generate_keypair() -> (ReusableSecret, PublicKey) {
@@ -45,3 +194,95 @@ pub mod reusable_key {
         reusable_key.diffie_hellman(&public_key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "getrandom")]
+    fn agree<D: DhFunction>() {
+        let (secret_1, public_1) = D::generate_keypair();
+        let (secret_2, public_2) = D::generate_keypair();
+        let shared_1 = D::dh(&secret_1, &public_2);
+        let shared_2 = D::dh(&secret_2, &public_1);
+        assert_eq!(shared_1.as_ref(), shared_2.as_ref());
+        assert_eq!(shared_1.as_ref().len(), D::DHLEN);
+        assert_eq!(public_1.as_ref().len(), D::DHLEN);
+    }
+
+    #[cfg(feature = "getrandom")]
+    #[test]
+    fn x25519_agreement() {
+        agree::<X25519>();
+    }
+
+    #[cfg(feature = "getrandom")]
+    #[test]
+    fn low_order_point_is_rejected() {
+        let (secret, public) = X25519::generate_keypair();
+        assert!(X25519::dh_checked(&secret, &public).is_ok());
+        // The identity point, the output of any scalar multiplication is zero.
+        let low_order = X25519::public_key_from_bytes(&[0u8; 32]).unwrap();
+        assert!(matches!(
+            X25519::dh_checked(&secret, &low_order),
+            Err(Error::NonContributoryDh)
+        ));
+    }
+
+    fn seeded<D: DhFunction>() {
+        use rand_chacha::{ChaCha20Rng, rand_core::SeedableRng};
+        let (_, public_1) = D::generate_keypair_with_rng(&mut ChaCha20Rng::from_seed([1; 32]));
+        let (_, public_2) = D::generate_keypair_with_rng(&mut ChaCha20Rng::from_seed([1; 32]));
+        let (_, public_3) = D::generate_keypair_with_rng(&mut ChaCha20Rng::from_seed([2; 32]));
+        assert_eq!(public_1.as_ref(), public_2.as_ref());
+        assert_ne!(public_1.as_ref(), public_3.as_ref());
+    }
+
+    #[test]
+    fn x25519_seeded_keypair() {
+        seeded::<X25519>();
+    }
+
+    #[cfg(feature = "x448")]
+    #[test]
+    fn x448_seeded_keypair() {
+        seeded::<X448>();
+    }
+
+    #[cfg(all(feature = "x448", feature = "getrandom"))]
+    #[test]
+    fn x448_agreement() {
+        agree::<X448>();
+    }
+
+    #[cfg(feature = "x448")]
+    #[test]
+    fn x448_rfc7748_vector() {
+        fn hex(s: &str) -> [u8; 56] {
+            let mut out = [0u8; 56];
+            for (i, b) in out.iter_mut().enumerate() {
+                *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+            }
+            out
+        }
+        // RFC 7748 section 6.2.
+        let alice = crate::Secret::new(hex(
+            "9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf574a9419744897391006382a6f127ab1d9ac2d8c0a598726b",
+        ));
+        let bob_public = hex(
+            "3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609",
+        );
+        assert_eq!(
+            X448::public_key(&alice),
+            hex(
+                "9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bbc836647241d953d40c5b12da88120d53177f80e532c41fa0"
+            )
+        );
+        assert_eq!(
+            X448::dh(&alice, &bob_public).expose(),
+            &hex(
+                "07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56fd2464c335543936521c24403085d59a449a5037514a879d"
+            )
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use alloc::string::String;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("HKDF output of {0} bytes is longer than 255 * HASHLEN")]
+    HkdfOutputTooLong(usize),
+    #[error("DH output is all zero, the remote public key has low order")]
+    NonContributoryDh,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("encrypted stream is corrupted, truncated or reordered")]
+    StreamCorrupted,
+    #[cfg(feature = "std")]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid key file: {0}")]
+    InvalidKeyFile(&'static str),
+    #[error("key file holds a {found} key, expected {expected}")]
+    KeyFileDhMismatch {
+        expected: &'static str,
+        found: String,
+    },
+    #[error("key file is encrypted, a passphrase is required")]
+    PassphraseRequired,
+    #[error("wrong passphrase or corrupted key file")]
+    WrongPassphrase,
+}
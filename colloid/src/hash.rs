@@ -4,49 +4,172 @@
 pub const HASHLEN: usize = blake3::OUT_LEN;
 pub const BLOCKLEN: usize = blake3::BLOCK_LEN;
 
+/// The largest BLOCKLEN of the supported hash functions (BLAKE2b and SHA512).
+pub const MAX_BLOCKLEN: usize = 128;
+
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{ByteArray, Error, Secret, byte};
+
+/// Hash functions as described in the Noise spec.
+pub trait HashFunction {
+    /// Name used in Noise protocol names, e.g. `BLAKE2s`.
+    const NAME: &'static str;
+    const HASHLEN: usize;
+    const BLOCKLEN: usize;
+
+    /// A `HASHLEN`-byte output.
+    type Output: ByteArray;
+
+    /// HASH(data), where data is the concatenation of `parts`.
+    fn hash_many(parts: &[&[u8]]) -> Self::Output;
+
+    /// HMAC-HASH(key, data), as specified in RFC 2104.
+    fn hmac(key: &Self::Output, data: &[u8]) -> Self::Output {
+        // Keys are never longer than HASHLEN, so they only need to be padded to BLOCKLEN.
+        let mut ipad = Zeroizing::new([0x36u8; MAX_BLOCKLEN]);
+        let mut opad = Zeroizing::new([0x5cu8; MAX_BLOCKLEN]);
+        for (i, b) in key.as_ref().iter().enumerate() {
+            ipad[i] ^= b;
+            opad[i] ^= b;
+        }
+        let mut inner = Self::hash_many(&[&ipad[..Self::BLOCKLEN], data]);
+        let output = Self::hash_many(&[&opad[..Self::BLOCKLEN], inner.as_ref()]);
+        inner.zeroize();
+        output
+    }
+
+    /// HASH(data)
+    fn hash(data: &[u8]) -> Self::Output {
+        Self::hash_many(&[data])
+    }
+
+    /// HKDF(chaining_key, input_key_material, num_outputs), with `num_outputs` given by `N`, which
+    /// must be 2 or 3. `input_key_material` may be empty, as in `Split()`.
+    fn hkdf<const N: usize>(
+        chaining_key: &Self::Output,
+        input_key_material: &[u8],
+    ) -> [Secret<Self::Output>; N] {
+        const { assert!(N == 2 || N == 3, "HKDF produces 2 or 3 outputs") };
+        let temp_key = Secret::new(Self::hmac(chaining_key, input_key_material));
+        let mut outputs: [Secret<Self::Output>; N] =
+            core::array::from_fn(|_| Secret::new(Self::Output::zeroed()));
+        *outputs[0].expose_mut() = Self::hmac(temp_key.expose(), &byte(1));
+        for i in 1..N {
+            let data = Zeroizing::new([outputs[i - 1].as_ref(), &byte(i as u8 + 1)].concat());
+            *outputs[i].expose_mut() = Self::hmac(temp_key.expose(), &data);
+        }
+        outputs
+    }
+
+    /// HKDF with an `info` string and an output of any length up to `255 * HASHLEN` bytes, for
+    /// exporting key material. With an empty `info` the output starts with the outputs of `hkdf`.
+    fn hkdf_expand(
+        chaining_key: &Self::Output,
+        input_key_material: &[u8],
+        info: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        if out.len() > 255 * Self::HASHLEN {
+            return Err(Error::HkdfOutputTooLong(out.len()));
+        }
+        let temp_key = Secret::new(Self::hmac(chaining_key, input_key_material));
+        let mut previous: Option<Secret<Self::Output>> = None;
+        for (i, chunk) in out.chunks_mut(Self::HASHLEN).enumerate() {
+            let previous_block = previous.as_ref().map(|p| p.as_ref()).unwrap_or_default();
+            let data = Zeroizing::new([previous_block, info, &byte(i as u8 + 1)].concat());
+            let block = Secret::new(Self::hmac(temp_key.expose(), &data));
+            chunk.copy_from_slice(&block.as_ref()[..chunk.len()]);
+            previous = Some(block);
+        }
+        Ok(())
+    }
+}
+
+/// BLAKE3, with HMAC implemented as BLAKE3 keyed hashing. Not part of the Noise spec, so only
+/// quiclike peers understand it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3;
+
+impl HashFunction for Blake3 {
+    const NAME: &'static str = "BLAKE3";
+    const HASHLEN: usize = HASHLEN;
+    const BLOCKLEN: usize = BLOCKLEN;
+
+    type Output = [u8; HASHLEN];
+
+    fn hash_many(parts: &[&[u8]]) -> Self::Output {
+        let mut hasher = blake3::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        *hasher.finalize().as_bytes()
+    }
+
+    fn hmac(key: &Self::Output, data: &[u8]) -> Self::Output {
+        *blake3::keyed_hash(key, data).as_bytes()
+    }
+}
+
+// Implements HashFunction for a RustCrypto `Digest`.
+#[cfg(any(feature = "blake2", feature = "sha2"))]
+macro_rules! digest_hash {
+    ($(#[$meta:meta])* $name:ident, $noise_name:literal, $digest:ty, $hashlen:literal, $blocklen:literal) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default)]
+        pub struct $name;
+
+        impl HashFunction for $name {
+            const NAME: &'static str = $noise_name;
+            const HASHLEN: usize = $hashlen;
+            const BLOCKLEN: usize = $blocklen;
+
+            type Output = [u8; $hashlen];
+
+            fn hash_many(parts: &[&[u8]]) -> Self::Output {
+                use digest::Digest;
+                let mut hasher = <$digest>::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.finalize().into()
+            }
+        }
+    };
+}
+
+#[cfg(feature = "blake2")]
+digest_hash!(
+    /// BLAKE2s with a 32-byte output.
+    Blake2s, "BLAKE2s", blake2::Blake2s256, 32, 64
+);
+#[cfg(feature = "blake2")]
+digest_hash!(
+    /// BLAKE2b with a 64-byte output.
+    Blake2b, "BLAKE2b", blake2::Blake2b512, 64, 128
+);
+#[cfg(feature = "sha2")]
+digest_hash!(
+    /// SHA-256.
+    Sha256, "SHA256", sha2::Sha256, 32, 64
+);
+#[cfg(feature = "sha2")]
+digest_hash!(
+    /// SHA-512.
+    Sha512, "SHA512", sha2::Sha512, 64, 128
+);
+
 /// Hash the data at once.
 pub mod once {
+    #[cfg(feature = "rayon")]
     pub mod rayon {
 
-        use crate::byte;
         use crate::hash::HASHLEN;
 
         pub fn hash(data: &[u8]) -> blake3::Hash {
             blake3::Hasher::new().update_rayon(data).finalize()
         }
 
-        pub fn hkdf(
-            chaining_key: &[u8; HASHLEN],
-            input_key_material: &[u8],
-            num_out: u8,
-            out1: &mut [u8; HASHLEN],
-            out2: &mut [u8; HASHLEN],
-            out3: &mut [u8; HASHLEN],
-        ) {
-            let mac_temp_key = hmac(chaining_key, input_key_material);
-            let temp_key = mac_temp_key.as_bytes();
-            let mac_output1 = hmac(temp_key, &byte(1));
-            let output1 = mac_output1.as_bytes();
-            out1.copy_from_slice(output1);
-            let mut buf1 = [0u8; HASHLEN + 1];
-            buf1[..32].copy_from_slice(output1);
-            buf1[32..].copy_from_slice(&byte(2));
-            let mac_output2 = hmac(temp_key, &buf1);
-            let output2 = mac_output2.as_bytes();
-            out2.copy_from_slice(output2);
-            if num_out == 2 {
-                return;
-            }
-            if num_out == 3 {
-                let mut buf2 = [0u8; HASHLEN + 1];
-                buf2[..32].copy_from_slice(output2);
-                buf2[32..].copy_from_slice(&byte(3));
-                let mac_output3 = hmac(temp_key, &buf2);
-                let output3 = mac_output3.as_bytes();
-                out3.copy_from_slice(output3);
-            };
-        }
-
         pub fn hmac(chaining_key: &[u8; HASHLEN], data: &[u8]) -> blake3::Hash {
             blake3::Hasher::new_keyed(chaining_key)
                 .update_rayon(data)
@@ -54,159 +177,184 @@ pub mod once {
         }
     }
 
+    #[cfg(feature = "mmap")]
     pub mod mmap {
-        use std::path::PathBuf;
+        use std::path::Path;
 
-        use crate::byte;
-        use crate::dh::DHLEN;
+        use crate::Error;
         use crate::hash::HASHLEN;
 
-        pub fn hash(path: PathBuf) -> blake3::Hash {
-            blake3::Hasher::new()
-                .update_mmap(path)
-                .expect("failed to open file.")
-                .finalize()
+        pub fn hash(path: impl AsRef<Path>) -> Result<blake3::Hash, Error> {
+            Ok(blake3::Hasher::new().update_mmap(path)?.finalize())
         }
 
-        pub fn hkdf(
+        /// Keyed hash of a file, for authenticating large files on disk.
+        pub fn hmac_file(
             chaining_key: &[u8; HASHLEN],
-            input_key_material: &[u8; DHLEN],
-            num_out: u8,
-            out1: &mut [u8; HASHLEN],
-            out2: &mut [u8; HASHLEN],
-            out3: &mut [u8; HASHLEN],
-        ) {
-            let mac_temp_key = hmac(chaining_key, input_key_material);
-            let temp_key = mac_temp_key.as_bytes();
-            let mac_output1 = hmac(temp_key, &byte(1));
-            let output1 = mac_output1.as_bytes();
-            out1.copy_from_slice(output1);
-            let mut buf1 = [0u8; HASHLEN + 1];
-            buf1[..32].copy_from_slice(output1);
-            buf1[32..].copy_from_slice(&byte(2));
-            let mac_output2 = hmac(temp_key, &buf1);
-            let output2 = mac_output2.as_bytes();
-            out2.copy_from_slice(output2);
-            if num_out == 2 {
-                return;
-            }
-            if num_out == 3 {
-                let mut buf2 = [0u8; HASHLEN + 1];
-                buf2[..32].copy_from_slice(output2);
-                buf2[32..].copy_from_slice(&byte(3));
-                let mac_output3 = hmac(temp_key, &buf2);
-                let output3 = mac_output3.as_bytes();
-                out3.copy_from_slice(output3);
-            };
-        }
-
-        pub fn hmac(chaining_key: &[u8; HASHLEN], data: &[u8]) -> blake3::Hash {
-            blake3::keyed_hash(chaining_key, data)
+            path: impl AsRef<Path>,
+        ) -> Result<blake3::Hash, Error> {
+            Ok(blake3::Hasher::new_keyed(chaining_key)
+                .update_mmap(path)?
+                .finalize())
         }
     }
 
+    #[cfg(all(feature = "mmap", feature = "rayon"))]
     pub mod mmap_rayon {
-        use std::path::PathBuf;
+        use std::path::Path;
 
-        use crate::byte;
-        use crate::dh::DHLEN;
+        use crate::Error;
         use crate::hash::HASHLEN;
 
-        pub fn hash(path: PathBuf) -> blake3::Hash {
-            blake3::Hasher::new()
-                .update_mmap_rayon(path)
-                .expect("failed to open file")
-                .finalize()
+        pub fn hash(path: impl AsRef<Path>) -> Result<blake3::Hash, Error> {
+            Ok(blake3::Hasher::new().update_mmap_rayon(path)?.finalize())
         }
 
-        pub fn hkdf(
+        /// Keyed hash of a file, for authenticating large files on disk.
+        pub fn hmac_file(
             chaining_key: &[u8; HASHLEN],
-            input_key_material: &[u8; DHLEN],
-            num_out: u8,
-            out1: &mut [u8; HASHLEN],
-            out2: &mut [u8; HASHLEN],
-            out3: &mut [u8; HASHLEN],
-        ) {
-            let mac_temp_key = hmac(chaining_key, input_key_material);
-            let temp_key = mac_temp_key.as_bytes();
-            let mac_output1 = hmac(temp_key, &byte(1));
-            let output1 = mac_output1.as_bytes();
-            out1.copy_from_slice(output1);
-            let mut buf1 = [0u8; HASHLEN + 1];
-            buf1[..32].copy_from_slice(output1);
-            buf1[32..].copy_from_slice(&byte(2));
-            let mac_output2 = hmac(temp_key, &buf1);
-            let output2 = mac_output2.as_bytes();
-            out2.copy_from_slice(output2);
-            if num_out == 2 {
-                return;
-            }
-            if num_out == 3 {
-                let mut buf2 = [0u8; HASHLEN + 1];
-                buf2[..32].copy_from_slice(output2);
-                buf2[32..].copy_from_slice(&byte(3));
-                let mac_output3 = hmac(temp_key, &buf2);
-                let output3 = mac_output3.as_bytes();
-                out3.copy_from_slice(output3);
-            };
-        }
-
-        pub fn hmac(chaining_key: &[u8; HASHLEN], data: &[u8]) -> blake3::Hash {
-            blake3::Hasher::new_keyed(chaining_key)
-                .update_rayon(data)
-                .finalize()
+            path: impl AsRef<Path>,
+        ) -> Result<blake3::Hash, Error> {
+            Ok(blake3::Hasher::new_keyed(chaining_key)
+                .update_mmap_rayon(path)?
+                .finalize())
         }
     }
 }
 
+#[cfg(feature = "std")]
 pub mod stream {
     use std::io::Read;
 
-    use crate::byte;
-    use crate::dh::DHLEN;
+    use crate::Error;
     use crate::hash::HASHLEN;
 
-    pub fn hash(reader: impl Read) -> blake3::Hash {
-        blake3::Hasher::new()
-            .update_reader(reader)
-            .expect("failed to read from the reader.")
-            .finalize()
+    pub fn hash(reader: impl Read) -> Result<blake3::Hash, Error> {
+        Ok(blake3::Hasher::new().update_reader(reader)?.finalize())
     }
-    pub fn hkdf(
+    /// Keyed hash of everything read from the reader.
+    pub fn hmac_reader(
         chaining_key: &[u8; HASHLEN],
-        input_key_material: &[u8; DHLEN],
-        // reader: impl Read,
-        num_out: u8,
-        out1: &mut [u8; HASHLEN],
-        out2: &mut [u8; HASHLEN],
-        out3: &mut [u8; HASHLEN],
-    ) {
-        let mac_temp_key = hmac(chaining_key, input_key_material);
-        let temp_key = mac_temp_key.as_bytes();
-        let mac_output1 = hmac(temp_key, &byte(1));
-        let output1 = mac_output1.as_bytes();
-        out1.copy_from_slice(output1);
-        let mut buf1 = [0u8; HASHLEN + 1];
-        buf1[..32].copy_from_slice(output1);
-        buf1[32..].copy_from_slice(&byte(2));
-        let mac_output2 = hmac(temp_key, &buf1);
-        let output2 = mac_output2.as_bytes();
-        out2.copy_from_slice(output2);
-        if num_out == 2 {
-            return;
-        }
-        if num_out == 3 {
-            let mut buf2 = [0u8; HASHLEN + 1];
-            buf2[..32].copy_from_slice(output2);
-            buf2[32..].copy_from_slice(&byte(3));
-            let mac_output3 = hmac(temp_key, &buf2);
-            let output3 = mac_output3.as_bytes();
-            out3.copy_from_slice(output3);
-        };
-    }
-    pub fn hmac(chaining_key: &[u8; HASHLEN], data: &[u8]) -> blake3::Hash {
-        blake3::Hasher::new_keyed(chaining_key)
-            .update_rayon(data)
-            .finalize()
+        reader: impl Read,
+    ) -> Result<blake3::Hash, Error> {
+        Ok(blake3::Hasher::new_keyed(chaining_key)
+            .update_reader(reader)?
+            .finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blake3_hash_and_keyed_hmac() {
+        let key = [7u8; HASHLEN];
+        assert_eq!(
+            Blake3::hash_many(&[b"a", b"bc"]),
+            *blake3::hash(b"abc").as_bytes()
+        );
+        assert_eq!(
+            Blake3::hmac(&key, b"abc"),
+            *blake3::keyed_hash(&key, b"abc").as_bytes()
+        );
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn sha256_hash() {
+        // FIPS 180-2 "abc" test vector.
+        assert_eq!(Sha256::hash(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf],);
+        assert_eq!(Sha256::hash_many(&[b"a", b"bc"]), Sha256::hash(b"abc"));
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn hmac_matches_rfc2104() {
+        use hmac::{Hmac, Mac};
+        let key = [0x0bu8; 64];
+        let mut mac = Hmac::<sha2::Sha512>::new_from_slice(&key).unwrap();
+        mac.update(b"Hi There");
+        assert_eq!(
+            Sha512::hmac(&key, b"Hi There")[..],
+            mac.finalize().into_bytes()[..]
+        );
+    }
+
+    #[cfg(feature = "blake2")]
+    #[test]
+    fn blake2s_hmac_matches_rfc2104() {
+        use hmac::{Mac, SimpleHmac};
+        let key = [0x0bu8; 32];
+        let mut mac = SimpleHmac::<blake2::Blake2s256>::new_from_slice(&key).unwrap();
+        mac.update(b"Hi There");
+        assert_eq!(
+            Blake2s::hmac(&key, b"Hi There")[..],
+            mac.finalize().into_bytes()[..]
+        );
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn hkdf_outputs() {
+        let ck = [1u8; 32];
+        let [out1, out2, out3] = Sha256::hkdf(&ck, b"ikm");
+        let (out1, out2, out3) = (out1.expose(), out2.expose(), out3.expose());
+        let temp_key = Sha256::hmac(&ck, b"ikm");
+        assert_eq!(out1, &Sha256::hmac(&temp_key, &[1]));
+        assert_eq!(out2, &Sha256::hmac(&temp_key, &[&out1[..], &[2]].concat()));
+        assert_eq!(out3, &Sha256::hmac(&temp_key, &[&out2[..], &[3]].concat()));
+        let [two1, two2] = Sha256::hkdf::<2>(&ck, b"ikm");
+        assert_eq!((two1.expose(), two2.expose()), (out1, out2));
+        assert_eq!(Blake3::hkdf::<2>(&ck, &[]).len(), 2);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn hkdf_expand_matches_rfc5869() {
+        // RFC 5869 test case 1, the chaining key is the salt.
+        let ikm = [0x0bu8; 22];
+        let salt: [u8; 13] = core::array::from_fn(|i| i as u8);
+        let info: [u8; 10] = core::array::from_fn(|i| 0xf0 + i as u8);
+        let mut expected = [0u8; 42];
+        hkdf::Hkdf::<sha2::Sha256>::new(Some(&salt), &ikm)
+            .expand(&info, &mut expected)
+            .unwrap();
+        assert_eq!(expected[..4], [0x3c, 0xb2, 0x5f, 0x25]);
+
+        // Salts shorter than the block size are zero padded by HMAC.
+        let mut ck = [0u8; 32];
+        ck[..13].copy_from_slice(&salt);
+        let mut okm = [0u8; 42];
+        Sha256::hkdf_expand(&ck, &ikm, &info, &mut okm).unwrap();
+        assert_eq!(okm, expected);
+
+        let mut too_long = alloc::vec![0u8; 255 * 32 + 1];
+        assert!(Sha256::hkdf_expand(&ck, &ikm, &[], &mut too_long).is_err());
+    }
+
+    #[cfg(all(feature = "mmap", feature = "rayon"))]
+    #[test]
+    fn io_hashing() {
+        let path = std::env::temp_dir().join(format!("colloid-io-hashing-{}", std::process::id()));
+        std::fs::write(&path, b"Hash algo Blake3.").unwrap();
+        let key = [7u8; HASHLEN];
+
+        let expected = once::rayon::hash(b"Hash algo Blake3.");
+        assert_eq!(once::mmap::hash(&path).unwrap(), expected);
+        assert_eq!(once::mmap_rayon::hash(&path).unwrap(), expected);
+        assert_eq!(stream::hash(&b"Hash algo Blake3."[..]).unwrap(), expected);
+
+        let expected = once::rayon::hmac(&key, b"Hash algo Blake3.");
+        assert_eq!(once::mmap::hmac_file(&key, &path).unwrap(), expected);
+        assert_eq!(once::mmap_rayon::hmac_file(&key, &path).unwrap(), expected);
+        assert_eq!(
+            stream::hmac_reader(&key, &b"Hash algo Blake3."[..]).unwrap(),
+            expected
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(once::mmap::hash(&path), Err(crate::Error::Io(_))));
+        assert!(once::mmap_rayon::hmac_file(&key, &path).is_err());
     }
 }
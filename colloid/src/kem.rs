@@ -0,0 +1,195 @@
+//! The KEM mod follows the KEM functions of PQNoise: https://eprint.iacr.org/2022/539, used next to
+//! the DH functions for hybrid post-quantum handshakes.
+
+use rand_core::{CryptoRng, RngCore};
+
+/// KEM functions: GENERATE_KEYPAIR, ENCAPS and DECAPS.
+pub trait KemFunction {
+    /// Name used in protocol names, e.g. `MLKEM768`. The DH part of a hybrid protocol name is
+    /// `<dh>+<kem>`, e.g. `25519+MLKEM768`.
+    const NAME: &'static str;
+    /// Length in bytes of public (encapsulation) keys.
+    const PUBLICKEYLEN: usize;
+    /// Length in bytes of ciphertexts.
+    const CIPHERTEXTLEN: usize;
+
+    type SecretKey;
+    type PublicKey: AsRef<[u8]> + Clone;
+    type Ciphertext: AsRef<[u8]>;
+    type SharedSecret: AsRef<[u8]>;
+
+    /// Generates a keypair with randomness from `rng`.
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+    ) -> (Self::SecretKey, Self::PublicKey);
+
+    #[cfg(feature = "getrandom")]
+    fn generate_keypair() -> (Self::SecretKey, Self::PublicKey) {
+        Self::generate_keypair_with_rng(&mut rand_core::OsRng)
+    }
+
+    /// Parses a `PUBLICKEYLEN`-byte public key, returns `None` if the length is wrong.
+    fn public_key_from_bytes(bytes: &[u8]) -> Option<Self::PublicKey>;
+
+    /// Parses a `CIPHERTEXTLEN`-byte ciphertext, returns `None` if the length is wrong.
+    fn ciphertext_from_bytes(bytes: &[u8]) -> Option<Self::Ciphertext>;
+
+    /// ENCAPS(public_key), with randomness from `rng`.
+    fn encapsulate_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        rng: &mut R,
+        public_key: &Self::PublicKey,
+    ) -> (Self::Ciphertext, Self::SharedSecret);
+
+    /// ENCAPS(public_key)
+    #[cfg(feature = "getrandom")]
+    fn encapsulate(public_key: &Self::PublicKey) -> (Self::Ciphertext, Self::SharedSecret) {
+        Self::encapsulate_with_rng(&mut rand_core::OsRng, public_key)
+    }
+
+    /// DECAPS(secret_key, ciphertext)
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        ciphertext: &Self::Ciphertext,
+    ) -> Option<Self::SharedSecret>;
+}
+
+/// The KEM of handshakes which are not hybrid. It has no keys, so none of its functions can ever
+/// be called: handshake patterns with KEM tokens are rejected when it is used.
+#[derive(Debug, Clone, Copy)]
+pub enum NoKem {}
+
+impl KemFunction for NoKem {
+    const NAME: &'static str = "";
+    const PUBLICKEYLEN: usize = 0;
+    const CIPHERTEXTLEN: usize = 0;
+
+    type SecretKey = NoKem;
+    type PublicKey = [u8; 0];
+    type Ciphertext = [u8; 0];
+    type SharedSecret = [u8; 0];
+
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        _rng: &mut R,
+    ) -> (Self::SecretKey, Self::PublicKey) {
+        unreachable!("NoKem has no key pairs")
+    }
+
+    fn public_key_from_bytes(_bytes: &[u8]) -> Option<Self::PublicKey> {
+        None
+    }
+
+    fn ciphertext_from_bytes(_bytes: &[u8]) -> Option<Self::Ciphertext> {
+        None
+    }
+
+    fn encapsulate_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        _rng: &mut R,
+        _public_key: &Self::PublicKey,
+    ) -> (Self::Ciphertext, Self::SharedSecret) {
+        unreachable!("NoKem has no public keys")
+    }
+
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        _ciphertext: &Self::Ciphertext,
+    ) -> Option<Self::SharedSecret> {
+        match *secret_key {}
+    }
+}
+
+/// ML-KEM-768 (FIPS 203).
+#[cfg(feature = "mlkem")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MlKem768;
+
+#[cfg(feature = "mlkem")]
+impl KemFunction for MlKem768 {
+    const NAME: &'static str = "MLKEM768";
+    const PUBLICKEYLEN: usize = 1184;
+    const CIPHERTEXTLEN: usize = 1088;
+
+    type SecretKey = <ml_kem::MlKem768 as ml_kem::KemCore>::DecapsulationKey;
+    type PublicKey = ml_kem::Encoded<<ml_kem::MlKem768 as ml_kem::KemCore>::EncapsulationKey>;
+    type Ciphertext = ml_kem::Ciphertext<ml_kem::MlKem768>;
+    type SharedSecret = crate::SecretKey;
+
+    fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        mut rng: &mut R,
+    ) -> (Self::SecretKey, Self::PublicKey) {
+        use ml_kem::{EncodedSizeUser, KemCore};
+        let (secret_key, public_key) = ml_kem::MlKem768::generate(&mut rng);
+        (secret_key, public_key.as_bytes())
+    }
+
+    fn public_key_from_bytes(bytes: &[u8]) -> Option<Self::PublicKey> {
+        bytes.try_into().ok()
+    }
+
+    fn ciphertext_from_bytes(bytes: &[u8]) -> Option<Self::Ciphertext> {
+        bytes.try_into().ok()
+    }
+
+    fn encapsulate_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        mut rng: &mut R,
+        public_key: &Self::PublicKey,
+    ) -> (Self::Ciphertext, Self::SharedSecret) {
+        use ml_kem::EncodedSizeUser;
+        use ml_kem::kem::Encapsulate;
+        let public_key =
+            <ml_kem::MlKem768 as ml_kem::KemCore>::EncapsulationKey::from_bytes(public_key);
+        let (ciphertext, shared_key) = public_key
+            .encapsulate(&mut rng)
+            .expect("ML-KEM encapsulation is infallible");
+        (ciphertext, shared_secret(shared_key))
+    }
+
+    // ML-KEM uses implicit rejection, a bad ciphertext yields an unrelated shared secret.
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        ciphertext: &Self::Ciphertext,
+    ) -> Option<Self::SharedSecret> {
+        use ml_kem::kem::Decapsulate;
+        secret_key.decapsulate(ciphertext).ok().map(shared_secret)
+    }
+}
+
+// Moves an ML-KEM shared key into a `SecretKey`, wiping the original.
+#[cfg(feature = "mlkem")]
+fn shared_secret(mut shared_key: ml_kem::SharedKey<ml_kem::MlKem768>) -> crate::SecretKey {
+    use zeroize::Zeroize;
+    let mut secret = crate::SecretKey::new([0u8; 32]);
+    secret.expose_mut().copy_from_slice(&shared_key);
+    shared_key.as_mut_slice().zeroize();
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "mlkem", feature = "getrandom"))]
+    #[test]
+    fn mlkem768_round_trip() {
+        use super::*;
+        let (secret_key, public_key) = MlKem768::generate_keypair();
+        assert_eq!(public_key.len(), MlKem768::PUBLICKEYLEN);
+        let (ciphertext, shared_1) = MlKem768::encapsulate(&public_key);
+        assert_eq!(ciphertext.len(), MlKem768::CIPHERTEXTLEN);
+        let ciphertext = MlKem768::ciphertext_from_bytes(ciphertext.as_ref()).unwrap();
+        let shared_2 = MlKem768::decapsulate(&secret_key, &ciphertext).unwrap();
+        assert_eq!(shared_1.expose(), shared_2.expose());
+    }
+
+    #[cfg(feature = "mlkem")]
+    #[test]
+    fn mlkem768_seeded() {
+        use super::*;
+        use rand_chacha::{ChaCha20Rng, rand_core::SeedableRng};
+        let mut rng_1 = ChaCha20Rng::from_seed([1; 32]);
+        let mut rng_2 = ChaCha20Rng::from_seed([1; 32]);
+        let (_, public_1) = MlKem768::generate_keypair_with_rng(&mut rng_1);
+        let (_, public_2) = MlKem768::generate_keypair_with_rng(&mut rng_2);
+        assert_eq!(public_1, public_2);
+        let (ciphertext_1, _) = MlKem768::encapsulate_with_rng(&mut rng_1, &public_1);
+        let (ciphertext_2, _) = MlKem768::encapsulate_with_rng(&mut rng_2, &public_2);
+        assert_eq!(ciphertext_1, ciphertext_2);
+    }
+}
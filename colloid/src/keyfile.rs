@@ -0,0 +1,290 @@
+//! Key files for static (identity) keypairs.
+//!
+//! A key file is armored text:
+//!
+//! ```text
+//! -----BEGIN QUICLIKE STATIC KEY-----
+//! DH: 25519
+//! Public-Key: <hex encoded public key>
+//! Encryption: none
+//!
+//! <base64 encoded body, wrapped at 64 columns>
+//! -----END QUICLIKE STATIC KEY-----
+//! ```
+//!
+//! With `Encryption: none` the body is the raw private key. With `Encryption: argon2id-cipher`
+//! the body is a 16-byte salt followed by the private key encrypted with the default colloid
+//! [`Cipher`] under `Argon2id(passphrase, salt)`, with nonce 0 and the header lines as associated
+//! data. The public key is kept in clear text, so the identity of a server can be read without
+//! its passphrase, and is checked against the private key when loading.
+
+use std::fs;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::cipher::Cipher;
+use crate::{CipherFunction, DhFunction, Error, SecretKey};
+
+const BEGIN: &str = "-----BEGIN QUICLIKE STATIC KEY-----";
+const END: &str = "-----END QUICLIKE STATIC KEY-----";
+const SALTLEN: usize = 16;
+
+/// Generates a new static keypair and saves it to `path`.
+pub fn generate<D: DhFunction>(
+    path: impl AsRef<Path>,
+    passphrase: Option<&[u8]>,
+) -> Result<(D::PrivateKey, D::PublicKey), Error> {
+    let (private_key, public_key) = D::generate_keypair();
+    save::<D>(path, &private_key, passphrase)?;
+    Ok((private_key, public_key))
+}
+
+/// Saves a static private key to `path`, readable by the owner only on unix.
+///
+/// The key is written to a new file next to `path` which is then renamed over it, so an existing
+/// key file is replaced whole and never keeps its old permissions.
+pub fn save<D: DhFunction>(
+    path: impl AsRef<Path>,
+    private_key: &D::PrivateKey,
+    passphrase: Option<&[u8]>,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let armored = encode::<D>(private_key, passphrase);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = path.with_file_name(name);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options.open(&tmp).and_then(|mut file| {
+        std::io::Write::write_all(&mut file, armored.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(written?)
+}
+
+/// Loads a static keypair from `path`, `passphrase` is required if the key file is encrypted.
+pub fn load<D: DhFunction>(
+    path: impl AsRef<Path>,
+    passphrase: Option<&[u8]>,
+) -> Result<(D::PrivateKey, D::PublicKey), Error> {
+    let armored = Zeroizing::new(fs::read_to_string(path)?);
+    decode::<D>(&armored, passphrase)
+}
+
+/// Encodes a static private key as an armored key file.
+pub fn encode<D: DhFunction>(private_key: &D::PrivateKey, passphrase: Option<&[u8]>) -> String {
+    let public_key = D::public_key(private_key);
+    let header = header::<D>(public_key.as_ref(), passphrase.is_some());
+    let body = match passphrase {
+        None => Zeroizing::new(D::private_key_bytes(private_key).to_vec()),
+        Some(passphrase) => {
+            let mut salt = [0u8; SALTLEN];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = Cipher::new(derive_key(passphrase, &salt));
+            let ciphertext =
+                cipher.encrypt(0, header.as_bytes(), D::private_key_bytes(private_key));
+            Zeroizing::new([&salt[..], &ciphertext].concat())
+        }
+    };
+
+    let body = Zeroizing::new(BASE64.encode(&*body));
+    let mut armored = format!("{BEGIN}\n{header}\n");
+    for line in body.as_bytes().chunks(64) {
+        // Base64 output is ASCII.
+        armored.push_str(std::str::from_utf8(line).unwrap_or_default());
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+    armored
+}
+
+/// Decodes an armored key file.
+pub fn decode<D: DhFunction>(
+    armored: &str,
+    passphrase: Option<&[u8]>,
+) -> Result<(D::PrivateKey, D::PublicKey), Error> {
+    let mut lines = armored.lines().map(str::trim);
+    if lines.next() != Some(BEGIN) {
+        return Err(Error::InvalidKeyFile("missing BEGIN line"));
+    }
+
+    let mut fields = Vec::new();
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+        fields.push(line);
+    }
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+            .ok_or(Error::InvalidKeyFile("missing header field"))
+    };
+    let dh = field("DH")?;
+    if dh != D::NAME {
+        return Err(Error::KeyFileDhMismatch {
+            expected: D::NAME,
+            found: dh.to_string(),
+        });
+    }
+    let public_key = hex::decode(field("Public-Key")?)
+        .ok()
+        .and_then(|bytes| D::public_key_from_bytes(&bytes))
+        .ok_or(Error::InvalidKeyFile("invalid public key"))?;
+    let encrypted = match field("Encryption")? {
+        "none" => false,
+        "argon2id-cipher" => true,
+        _ => return Err(Error::InvalidKeyFile("unknown encryption")),
+    };
+
+    let mut body = Zeroizing::new(String::new());
+    let mut ended = false;
+    for line in lines {
+        if line == END {
+            ended = true;
+            break;
+        }
+        body.push_str(line);
+    }
+    if !ended {
+        return Err(Error::InvalidKeyFile("missing END line"));
+    }
+    let body = Zeroizing::new(
+        BASE64
+            .decode(body.as_bytes())
+            .map_err(|_| Error::InvalidKeyFile("invalid base64 body"))?,
+    );
+
+    let private_key_bytes = if encrypted {
+        let passphrase = passphrase.ok_or(Error::PassphraseRequired)?;
+        if body.len() < SALTLEN {
+            return Err(Error::InvalidKeyFile("body too short"));
+        }
+        let (salt, ciphertext) = body.split_at(SALTLEN);
+        let cipher = Cipher::new(derive_key(passphrase, salt));
+        let ad = header::<D>(public_key.as_ref(), true);
+        Zeroizing::new(
+            cipher
+                .decrypt(0, ad.as_bytes(), ciphertext)
+                .map_err(|_| Error::WrongPassphrase)?,
+        )
+    } else {
+        body
+    };
+
+    let private_key = D::private_key_from_bytes(&private_key_bytes)
+        .ok_or(Error::InvalidKeyFile("invalid private key"))?;
+    if !crate::ct::eq(D::public_key(&private_key).as_ref(), public_key.as_ref()) {
+        return Err(Error::InvalidKeyFile(
+            "public key does not match private key",
+        ));
+    }
+    Ok((private_key, public_key))
+}
+
+fn header<D: DhFunction>(public_key: &[u8], encrypted: bool) -> String {
+    let encryption = if encrypted { "argon2id-cipher" } else { "none" };
+    format!(
+        "DH: {}\nPublic-Key: {}\nEncryption: {encryption}\n",
+        D::NAME,
+        hex::encode(public_key)
+    )
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> SecretKey {
+    let mut key = SecretKey::new([0u8; 32]);
+    argon2::Argon2::default()
+        .hash_password_into(passphrase, salt, key.expose_mut())
+        .expect("Argon2 accepts 16-byte salts and 32-byte outputs");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dh::X25519;
+
+    #[test]
+    fn round_trip() {
+        let (private_key, public_key) = X25519::generate_keypair();
+        let armored = encode::<X25519>(&private_key, None);
+        let (loaded, loaded_public) = decode::<X25519>(&armored, None).unwrap();
+        assert_eq!(loaded.as_bytes(), private_key.as_bytes());
+        assert_eq!(loaded_public, public_key);
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let (private_key, _) = X25519::generate_keypair();
+        let armored = encode::<X25519>(&private_key, Some(b"correct horse"));
+        let (loaded, _) = decode::<X25519>(&armored, Some(b"correct horse")).unwrap();
+        assert_eq!(loaded.as_bytes(), private_key.as_bytes());
+
+        assert!(matches!(
+            decode::<X25519>(&armored, Some(b"battery staple")),
+            Err(Error::WrongPassphrase)
+        ));
+        assert!(matches!(
+            decode::<X25519>(&armored, None),
+            Err(Error::PassphraseRequired)
+        ));
+    }
+
+    #[test]
+    fn corrupted() {
+        let (private_key, _) = X25519::generate_keypair();
+        let armored = encode::<X25519>(&private_key, None);
+        let lines: Vec<&str> = armored.lines().collect();
+        // Replace the body with another key.
+        let (other, _) = X25519::generate_keypair();
+        let corrupted = armored.replace(lines[5], &BASE64.encode(other.as_bytes()));
+        assert!(matches!(
+            decode::<X25519>(&corrupted, None),
+            Err(Error::InvalidKeyFile(_))
+        ));
+        assert!(matches!(
+            decode::<X25519>(&armored.replace("DH: 25519", "DH: 448"), None),
+            Err(Error::KeyFileDhMismatch { .. })
+        ));
+        assert!(decode::<X25519>(&armored[..armored.len() - 10], None).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("colloid-keyfile-{}", std::process::id()));
+        let (private_key, public_key) = generate::<X25519>(&path, None).unwrap();
+        let (loaded, loaded_public) = load::<X25519>(&path, None).unwrap();
+        assert_eq!(loaded.as_bytes(), private_key.as_bytes());
+        assert_eq!(loaded_public, public_key);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_replaces_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("colloid-keyfile-mode-{}", std::process::id()));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let (private_key, _) = generate::<X25519>(&path, None).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let (loaded, _) = load::<X25519>(&path, None).unwrap();
+        assert_eq!(loaded.as_bytes(), private_key.as_bytes());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,48 @@
+//! Without the `std` feature colloid is `no_std` and only needs `alloc`; file hashing, key files,
+//! streaming encryption, parallel batches and the ChaCha12-BLAKE3 `cipher::Cipher` are std only.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "rayon")]
+pub mod batch;
 pub mod cipher;
+pub mod ct;
 pub mod dh;
+pub mod error;
 pub mod hash;
+pub mod kem;
+#[cfg(feature = "keyfile")]
+pub mod keyfile;
+pub mod secret;
+#[cfg(feature = "ed25519")]
+pub mod sign;
+#[cfg(feature = "std")]
+pub mod stream;
+pub use aead;
 pub use blake3;
+pub use rand_core;
+
+pub use cipher::CipherFunction;
+pub use dh::DhFunction;
+pub use error::Error;
+pub use hash::HashFunction;
+pub use kem::KemFunction;
+pub use secret::{Secret, SecretKey};
 
 #[inline(always)]
 pub fn byte(n: u8) -> [u8; 1] {
     [n]
 }
+
+/// Fixed-size byte arrays, used for the outputs of the crypto functions.
+pub trait ByteArray: AsRef<[u8]> + AsMut<[u8]> + Clone + zeroize::Zeroize {
+    /// An all-zero array.
+    fn zeroed() -> Self;
+}
+
+impl<const N: usize> ByteArray for [u8; N] {
+    fn zeroed() -> Self {
+        [0u8; N]
+    }
+}
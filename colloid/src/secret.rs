@@ -0,0 +1,70 @@
+//! Wrappers for secret material (keys, chaining keys, DH outputs).
+
+use core::fmt;
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// A secret value which is wiped when dropped. It is not `Copy`, so every copy has to be made
+/// with an explicit `clone()`, and its `Debug` output never shows the value.
+pub struct Secret<T: Zeroize>(T);
+
+/// A 32-byte cipher key.
+pub type SecretKey = Secret<[u8; 32]>;
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> AsRef<[u8]> for Secret<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> ZeroizeOnDrop for Secret<T> {}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let key = SecretKey::new([0x42u8; 32]);
+        assert_eq!(alloc::format!("{key:?}"), "Secret([REDACTED])");
+        assert_eq!(key.clone().expose(), &[0x42u8; 32]);
+    }
+}
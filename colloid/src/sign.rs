@@ -0,0 +1,75 @@
+//! Ed25519 signatures, used for identities which vouch for static Noise keys (see the
+//! certificates of smog).
+
+use ed25519_dalek::Signer;
+use rand_core::{CryptoRng, RngCore};
+
+use crate::Error;
+
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+/// Length in bytes of public (verifying) keys.
+pub const PUBLICKEYLEN: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+/// Length in bytes of signatures.
+pub const SIGNATURELEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+/// Generates a signing key with randomness from `rng`.
+pub fn generate_keypair_with_rng<R: RngCore + CryptoRng + ?Sized>(
+    rng: &mut R,
+) -> (SigningKey, VerifyingKey) {
+    let mut seed = zeroize::Zeroizing::new([0u8; 32]);
+    rng.fill_bytes(&mut *seed);
+    let signing_key = SigningKey::from_bytes(&seed);
+    let verifying_key = signing_key.verifying_key();
+    (signing_key, verifying_key)
+}
+
+#[cfg(feature = "getrandom")]
+pub fn generate_keypair() -> (SigningKey, VerifyingKey) {
+    generate_keypair_with_rng(&mut rand_core::OsRng)
+}
+
+pub fn sign(signing_key: &SigningKey, message: &[u8]) -> Signature {
+    signing_key.sign(message)
+}
+
+/// Verifies `signature` with the strict checks of ed25519-dalek, which reject malleable
+/// signatures and weak public keys.
+pub fn verify(
+    verifying_key: &VerifyingKey,
+    message: &[u8],
+    signature: &Signature,
+) -> Result<(), Error> {
+    verifying_key
+        .verify_strict(message, signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+/// Parses a `PUBLICKEYLEN`-byte verifying key, returns `None` if it is not a valid point.
+pub fn verifying_key_from_bytes(bytes: &[u8]) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(bytes.try_into().ok()?).ok()
+}
+
+/// Parses a `SIGNATURELEN`-byte signature, returns `None` if the length is wrong.
+pub fn signature_from_bytes(bytes: &[u8]) -> Option<Signature> {
+    Some(Signature::from_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(all(test, feature = "getrandom"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let (signing_key, verifying_key) = generate_keypair();
+        let signature = sign(&signing_key, b"message");
+        assert!(verify(&verifying_key, b"message", &signature).is_ok());
+        assert!(matches!(
+            verify(&verifying_key, b"massage", &signature),
+            Err(Error::InvalidSignature)
+        ));
+        let signature = signature_from_bytes(&signature.to_bytes()).unwrap();
+        let verifying_key = verifying_key_from_bytes(verifying_key.as_bytes()).unwrap();
+        assert!(verify(&verifying_key, b"message", &signature).is_ok());
+    }
+}
@@ -0,0 +1,342 @@
+//! Segmented AEAD over `std::io`, following the STREAM construction (Hoang, Reyhanitabar,
+//! Rogaway and Vizár, "Online Authenticated-Encryption and its Nonce-Reuse Misuse-Resistance").
+//!
+//! ```text
+//! header  = salt (32 bytes) || chunk_size (u32, little-endian)
+//! chunk_i = ENCRYPT(k, i | last << 63, header, plaintext_i)
+//! ```
+//!
+//! `k` is derived from the caller's key and the random salt with BLAKE3 `derive_key`, so one key
+//! can encrypt many streams. Every chunk but the last holds exactly `chunk_size` bytes of
+//! plaintext and the last one is always shorter (possibly empty), which is how the reader finds it
+//! without lookahead. The chunk counter in the nonce rejects reordered chunks and the last flag
+//! rejects truncated streams, including streams cut at a chunk boundary.
+
+use std::io::{self, Read, Write};
+
+use rand_core::{OsRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::cipher::Cipher;
+use crate::{CipherFunction, Error, SecretKey};
+
+/// Default plaintext bytes per chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Largest chunk size accepted by the reader, so a forged header can't make it allocate much.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const SALTLEN: usize = 32;
+const HEADERLEN: usize = SALTLEN + 4;
+const KDF_CONTEXT: &str = "quiclike colloid 2025-06 STREAM key";
+const LAST: u64 = 1 << 63;
+
+fn stream_key(key: &SecretKey, salt: &[u8]) -> SecretKey {
+    let mut hasher = blake3::Hasher::new_derive_key(KDF_CONTEXT);
+    hasher.update(key.expose());
+    hasher.update(salt);
+    SecretKey::new(hasher.finalize().into())
+}
+
+fn nonce(counter: u64, last: bool) -> io::Result<u64> {
+    if counter >= LAST {
+        return Err(io::Error::other("STREAM chunk counter exhausted"));
+    }
+    Ok(if last { counter | LAST } else { counter })
+}
+
+fn invalid_data(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Encrypts everything written to it into `inner`. [`EncryptWriter::finish`] has to be called to
+/// write the last chunk, otherwise the stream is rejected as truncated.
+pub struct EncryptWriter<W: Write, C: CipherFunction = Cipher> {
+    inner: W,
+    cipher: C,
+    header: [u8; HEADERLEN],
+    chunk_size: usize,
+    counter: u64,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: &SecretKey) -> io::Result<Self> {
+        Self::with_chunk_size(inner, key, CHUNK_SIZE)
+    }
+}
+
+impl<W: Write, C: CipherFunction> EncryptWriter<W, C> {
+    /// Like `new`, with `chunk_size` bytes of plaintext per chunk, up to [`MAX_CHUNK_SIZE`]. Fails
+    /// with [`io::ErrorKind::InvalidInput`] for a chunk size out of range.
+    pub fn with_chunk_size(mut inner: W, key: &SecretKey, chunk_size: usize) -> io::Result<Self> {
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid STREAM chunk size",
+            ));
+        }
+        let mut header = [0u8; HEADERLEN];
+        OsRng.fill_bytes(&mut header[..SALTLEN]);
+        header[SALTLEN..].copy_from_slice(&(chunk_size as u32).to_le_bytes());
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            cipher: C::new(stream_key(key, &header[..SALTLEN])),
+            header,
+            chunk_size,
+            counter: 0,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size + C::TAGLEN)),
+        })
+    }
+
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let n = nonce(self.counter, last)?;
+        self.cipher
+            .encrypt_in_place(n, &self.header, &mut *self.buffer)
+            .map_err(|_| io::Error::other("STREAM chunk encryption failed"))?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Writes the last chunk and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write, C: CipherFunction> Write for EncryptWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        // A full chunk is never the last one, the last chunk is always shorter.
+        if self.buffer.len() == self.chunk_size {
+            self.write_chunk(false)?;
+        }
+        Ok(len)
+    }
+
+    // Only whole chunks are written, a partial chunk stays buffered until it fills or `finish`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by [`EncryptWriter`]. Reads fail with `InvalidData` if a chunk is
+/// forged, reordered or missing; plaintext is only returned once its chunk is authenticated. After
+/// any error the reader is poisoned and every later read fails, a partially read chunk can't be
+/// resumed.
+pub struct DecryptReader<R: Read, C: CipherFunction = Cipher> {
+    inner: R,
+    cipher: C,
+    header: [u8; HEADERLEN],
+    chunk_size: usize,
+    counter: u64,
+    buffer: Zeroizing<Vec<u8>>,
+    position: usize,
+    finished: bool,
+    failed: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(inner: R, key: &SecretKey) -> io::Result<Self> {
+        Self::with_cipher(inner, key)
+    }
+}
+
+impl<R: Read, C: CipherFunction> DecryptReader<R, C> {
+    /// Like `new`, for streams encrypted with the cipher `C`.
+    pub fn with_cipher(mut inner: R, key: &SecretKey) -> io::Result<Self> {
+        let mut header = [0u8; HEADERLEN];
+        inner.read_exact(&mut header)?;
+        let chunk_size = u32::from_le_bytes(header[SALTLEN..].try_into().unwrap()) as usize;
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(invalid_data(Error::StreamCorrupted));
+        }
+        Ok(Self {
+            inner,
+            cipher: C::new(stream_key(key, &header[..SALTLEN])),
+            header,
+            chunk_size,
+            counter: 0,
+            buffer: Zeroizing::new(Vec::with_capacity(chunk_size + C::TAGLEN)),
+            position: 0,
+            finished: false,
+            failed: false,
+        })
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let full = self.chunk_size + C::TAGLEN;
+        self.buffer.resize(full, 0);
+        let mut len = 0;
+        while len < full {
+            match self.inner.read(&mut self.buffer[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.buffer.truncate(len);
+
+        let last = len < full;
+        let n = nonce(self.counter, last)?;
+        self.cipher
+            .decrypt_in_place(n, &self.header, &mut *self.buffer)
+            .map_err(|_| invalid_data(Error::StreamCorrupted))?;
+        self.counter += 1;
+        self.position = 0;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read, C: CipherFunction> Read for DecryptReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::other("STREAM reader failed on an earlier error"));
+        }
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            if let Err(e) = self.read_chunk() {
+                // The buffer holds unauthenticated bytes or a partial chunk, never hand them out.
+                self.buffer.clear();
+                self.position = 0;
+                self.failed = true;
+                return Err(e);
+            }
+        }
+        let len = buf.len().min(self.buffer.len() - self.position);
+        buf[..len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7u8; 32];
+
+    fn encrypt(plaintext: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut writer =
+            EncryptWriter::<_, Cipher>::with_chunk_size(Vec::new(), &KEY.into(), chunk_size)
+                .unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        DecryptReader::new(ciphertext, &KEY.into())?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        for len in [0, 1, 99, 100, 101, 300, 1000] {
+            let ciphertext = encrypt(&data[..len], 100);
+            assert_eq!(decrypt(&ciphertext).unwrap(), &data[..len]);
+        }
+        assert_eq!(decrypt(&encrypt(&data, CHUNK_SIZE)).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_truncation_and_reordering() {
+        let chunk = 100 + Cipher::TAGLEN;
+        let ciphertext = encrypt(&[42u8; 250], 100);
+        // Header, two full chunks and a last chunk of 50 bytes.
+        assert_eq!(
+            ciphertext.len(),
+            HEADERLEN + 2 * chunk + 50 + Cipher::TAGLEN
+        );
+
+        let invalid = |ciphertext: &[u8]| {
+            decrypt(ciphertext).unwrap_err().kind() == io::ErrorKind::InvalidData
+        };
+        // Cut at a chunk boundary and in the middle of a chunk.
+        assert!(invalid(&ciphertext[..HEADERLEN + 2 * chunk]));
+        assert!(invalid(&ciphertext[..ciphertext.len() - 1]));
+        // Swap the two full chunks.
+        let mut reordered = ciphertext.clone();
+        reordered[HEADERLEN..HEADERLEN + chunk]
+            .copy_from_slice(&ciphertext[HEADERLEN + chunk..HEADERLEN + 2 * chunk]);
+        reordered[HEADERLEN + chunk..HEADERLEN + 2 * chunk]
+            .copy_from_slice(&ciphertext[HEADERLEN..HEADERLEN + chunk]);
+        assert!(invalid(&reordered));
+        // Trailing data after the last chunk.
+        assert!(invalid(&[&ciphertext[..], &[0u8]].concat()));
+        // A writer dropped without `finish`.
+        let mut writer = EncryptWriter::new(Vec::new(), &KEY.into()).unwrap();
+        writer.write_all(b"unfinished").unwrap();
+        assert!(invalid(&writer.inner));
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_size() {
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1] {
+            let mut out = Vec::new();
+            let error =
+                EncryptWriter::<_, Cipher>::with_chunk_size(&mut out, &KEY.into(), chunk_size)
+                    .err()
+                    .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(out.is_empty());
+        }
+    }
+
+    // Returns `WouldBlock` once when reaching `fail_at`.
+    struct WouldBlockOnce<'a> {
+        data: &'a [u8],
+        fail_at: usize,
+    }
+
+    impl Read for WouldBlockOnce<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.fail_at == 0 {
+                self.fail_at = usize::MAX;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(self.fail_at).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            self.fail_at -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn poisoned_after_error() {
+        let ciphertext = encrypt(&[42u8; 250], 100);
+        let mut buf = [0u8; 50];
+
+        // Cut in the middle of the first chunk.
+        let inner = WouldBlockOnce {
+            data: &ciphertext,
+            fail_at: HEADERLEN + 60,
+        };
+        let mut reader = DecryptReader::new(inner, &KEY.into()).unwrap();
+        let error = reader.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(reader.read(&mut buf).is_err());
+        assert!(reader.read(&mut buf).is_err());
+
+        let mut corrupted = ciphertext.clone();
+        corrupted[HEADERLEN + 100 + Cipher::TAGLEN + 1] ^= 1;
+        let mut reader = DecryptReader::new(&corrupted[..], &KEY.into()).unwrap();
+        let mut plaintext = [0u8; 100];
+        reader.read_exact(&mut plaintext).unwrap();
+        assert_eq!(plaintext, [42u8; 100]);
+        let error = reader.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
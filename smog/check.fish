@@ -1,4 +1,6 @@
 #!/usr/bin/fish
 cargo check
 cargo clippy
+# colloid and smog are no_std + alloc without default features.
+cargo check -p colloid -p smog --no-default-features
 echo Project checked.
@@ -0,0 +1,375 @@
+//! Compact certificates binding a static Noise key to an Ed25519 issuer (e.g. the operator key of a
+//! deployment), sent as a handshake payload and verified against the configured trust anchors.
+//!
+//! Wire format, integers are big-endian:
+//!
+//! ```text
+//! version          u8 (1)
+//! subject_len      u8
+//! subject          subject_len bytes, the static public key of the subject
+//! not_before       u64, unix seconds
+//! not_after        u64, unix seconds
+//! issuer           32 bytes, Ed25519 public key
+//! extension_count  u8
+//! extensions       extension_count * (id u16, len u16, len bytes)
+//! signature        64 bytes, Ed25519 over CONTEXT || all the preceding bytes
+//! ```
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use colloid::ct;
+use colloid::sign::{self, Signature, SigningKey, VerifyingKey};
+use thiserror::Error;
+
+use crate::Error;
+
+const VERSION: u8 = 1;
+// Domain separation, so a certificate signature can't be reused as another signature of the issuer.
+const CONTEXT: &[u8] = b"quiclike certificate v1";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CertificateError {
+    #[error("malformed certificate: {0}")]
+    Malformed(&'static str),
+    #[error("unsupported certificate version {0}")]
+    UnsupportedVersion(u8),
+    #[error("certificate issuer is not a trust anchor")]
+    UntrustedIssuer,
+    #[error("invalid certificate signature")]
+    InvalidSignature,
+    #[error("certificate is not valid yet")]
+    NotYetValid,
+    #[error("certificate has expired")]
+    Expired,
+    #[error("certificate subject does not match the remote static key")]
+    SubjectMismatch,
+}
+
+/// An opaque extension, identified by `id`. Extensions are signed with the certificate but not
+/// interpreted by smog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub id: u16,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    subject: Vec<u8>,
+    not_before: u64,
+    not_after: u64,
+    issuer: VerifyingKey,
+    extensions: Vec<Extension>,
+    signature: Signature,
+}
+
+impl Certificate {
+    /// Issues a certificate for the static public key `subject`, valid from `not_before` to
+    /// `not_after` (unix seconds, inclusive). Fails if the subject or the list of extensions
+    /// don't fit in the wire format.
+    pub fn issue(
+        issuer: &SigningKey,
+        subject: &[u8],
+        not_before: u64,
+        not_after: u64,
+        extensions: Vec<Extension>,
+    ) -> Result<Self, CertificateError> {
+        if subject.len() > u8::MAX as usize {
+            return Err(CertificateError::Malformed("subject too long"));
+        }
+        if extensions.len() > u8::MAX as usize {
+            return Err(CertificateError::Malformed("too many extensions"));
+        }
+        if extensions
+            .iter()
+            .any(|extension| extension.value.len() > u16::MAX as usize)
+        {
+            return Err(CertificateError::Malformed("extension value too long"));
+        }
+        let mut certificate = Self {
+            subject: subject.to_vec(),
+            not_before,
+            not_after,
+            issuer: issuer.verifying_key(),
+            extensions,
+            signature: Signature::from_bytes(&[0u8; sign::SIGNATURELEN]),
+        };
+        certificate.signature = sign::sign(issuer, &certificate.signed_message());
+        Ok(certificate)
+    }
+
+    pub fn subject(&self) -> &[u8] {
+        &self.subject
+    }
+
+    pub fn not_before(&self) -> u64 {
+        self.not_before
+    }
+
+    pub fn not_after(&self) -> u64 {
+        self.not_after
+    }
+
+    pub fn issuer(&self) -> &VerifyingKey {
+        &self.issuer
+    }
+
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    pub fn extension(&self, id: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.id == id)
+            .map(|extension| extension.value.as_slice())
+    }
+
+    // Everything but the signature. The lengths fit their prefixes, `issue` and `from_bytes`
+    // are the only ways to build a certificate and both check them.
+    fn tbs_bytes(&self) -> Vec<u8> {
+        let mut out = vec![VERSION, self.subject.len() as u8];
+        out.extend_from_slice(&self.subject);
+        out.extend_from_slice(&self.not_before.to_be_bytes());
+        out.extend_from_slice(&self.not_after.to_be_bytes());
+        out.extend_from_slice(self.issuer.as_bytes());
+        out.push(self.extensions.len() as u8);
+        for extension in &self.extensions {
+            out.extend_from_slice(&extension.id.to_be_bytes());
+            out.extend_from_slice(&(extension.value.len() as u16).to_be_bytes());
+            out.extend_from_slice(&extension.value);
+        }
+        out
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        [CONTEXT, &self.tbs_bytes()].concat()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.tbs_bytes();
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Parses a certificate, the signature is only checked by [`TrustAnchors::verify`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CertificateError> {
+        let mut reader = Reader(bytes);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(CertificateError::UnsupportedVersion(version));
+        }
+        let subject_len = reader.u8()? as usize;
+        let subject = reader.take(subject_len)?.to_vec();
+        let not_before = reader.u64()?;
+        let not_after = reader.u64()?;
+        let issuer = sign::verifying_key_from_bytes(reader.take(sign::PUBLICKEYLEN)?)
+            .ok_or(CertificateError::Malformed("invalid issuer key"))?;
+        let count = reader.u8()?;
+        let mut extensions = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = reader.u16()?;
+            let len = reader.u16()? as usize;
+            extensions.push(Extension {
+                id,
+                value: reader.take(len)?.to_vec(),
+            });
+        }
+        let signature = sign::signature_from_bytes(reader.take(sign::SIGNATURELEN)?)
+            .ok_or(CertificateError::Malformed("invalid signature"))?;
+        if !reader.0.is_empty() {
+            return Err(CertificateError::Malformed("trailing bytes"));
+        }
+        Ok(Self {
+            subject,
+            not_before,
+            not_after,
+            issuer,
+            extensions,
+            signature,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CertificateError> {
+        if self.0.len() < len {
+            return Err(CertificateError::Malformed("truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, CertificateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CertificateError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CertificateError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// The issuer keys trusted to vouch for static keys.
+#[derive(Debug, Clone, Default)]
+pub struct TrustAnchors {
+    issuers: Vec<VerifyingKey>,
+}
+
+impl TrustAnchors {
+    pub fn new(issuers: Vec<VerifyingKey>) -> Self {
+        Self { issuers }
+    }
+
+    pub fn add(&mut self, issuer: VerifyingKey) {
+        self.issuers.push(issuer);
+    }
+
+    /// Checks that `certificate` is issued by a trust anchor, is valid at `now` (unix seconds)
+    /// and vouches for `remote_static`, the static key the peer proved in the handshake.
+    pub fn verify(
+        &self,
+        certificate: &Certificate,
+        remote_static: &[u8],
+        now: u64,
+    ) -> Result<(), CertificateError> {
+        if !self
+            .issuers
+            .iter()
+            .any(|issuer| issuer.as_bytes() == certificate.issuer.as_bytes())
+        {
+            return Err(CertificateError::UntrustedIssuer);
+        }
+        sign::verify(
+            &certificate.issuer,
+            &certificate.signed_message(),
+            &certificate.signature,
+        )
+        .map_err(|_| CertificateError::InvalidSignature)?;
+        if now < certificate.not_before {
+            return Err(CertificateError::NotYetValid);
+        }
+        if now > certificate.not_after {
+            return Err(CertificateError::Expired);
+        }
+        if !ct::eq(&certificate.subject, remote_static) {
+            return Err(CertificateError::SubjectMismatch);
+        }
+        Ok(())
+    }
+
+    /// Parses and verifies a certificate received as a handshake payload.
+    pub fn verify_payload(
+        &self,
+        payload: &[u8],
+        remote_static: &[u8],
+        now: u64,
+    ) -> Result<Certificate, Error> {
+        let certificate = Certificate::from_bytes(payload)?;
+        self.verify(&certificate, remote_static, now)?;
+        Ok(certificate)
+    }
+}
+
+#[cfg(all(test, feature = "getrandom"))]
+mod tests {
+    use super::*;
+    use colloid::{DhFunction, dh::X25519};
+
+    #[test]
+    fn issue_and_verify() {
+        let (operator, operator_public) = sign::generate_keypair();
+        let (_, server_static) = X25519::generate_keypair();
+        let certificate = Certificate::issue(
+            &operator,
+            server_static.as_bytes(),
+            100,
+            200,
+            vec![Extension {
+                id: 1,
+                value: b"example.com".to_vec(),
+            }],
+        )
+        .unwrap();
+        let payload = certificate.to_bytes();
+        let anchors = TrustAnchors::new(vec![operator_public]);
+
+        let parsed = anchors
+            .verify_payload(&payload, server_static.as_bytes(), 150)
+            .unwrap();
+        assert_eq!(parsed, certificate);
+        assert_eq!(parsed.extension(1), Some(&b"example.com"[..]));
+
+        assert_eq!(
+            anchors.verify(&parsed, server_static.as_bytes(), 99),
+            Err(CertificateError::NotYetValid)
+        );
+        assert_eq!(
+            anchors.verify(&parsed, server_static.as_bytes(), 201),
+            Err(CertificateError::Expired)
+        );
+        let (_, other_static) = X25519::generate_keypair();
+        assert_eq!(
+            anchors.verify(&parsed, other_static.as_bytes(), 150),
+            Err(CertificateError::SubjectMismatch)
+        );
+        assert_eq!(
+            TrustAnchors::default().verify(&parsed, server_static.as_bytes(), 150),
+            Err(CertificateError::UntrustedIssuer)
+        );
+
+        // Tampering with the validity window breaks the signature.
+        let mut tampered = payload.clone();
+        tampered[2 + 32 + 15] ^= 1;
+        let tampered = Certificate::from_bytes(&tampered).unwrap();
+        assert_eq!(
+            anchors.verify(&tampered, server_static.as_bytes(), 150),
+            Err(CertificateError::InvalidSignature)
+        );
+        assert!(Certificate::from_bytes(&payload[..payload.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn issue_rejects_oversize_fields() {
+        let (operator, _) = sign::generate_keypair();
+        assert_eq!(
+            Certificate::issue(&operator, &[0u8; 256], 0, 1, Vec::new()),
+            Err(CertificateError::Malformed("subject too long"))
+        );
+        let extensions = vec![
+            Extension {
+                id: 1,
+                value: Vec::new(),
+            };
+            256
+        ];
+        assert_eq!(
+            Certificate::issue(&operator, &[0u8; 32], 0, 1, extensions),
+            Err(CertificateError::Malformed("too many extensions"))
+        );
+        let extensions = vec![Extension {
+            id: 1,
+            value: vec![0u8; u16::MAX as usize + 1],
+        }];
+        assert_eq!(
+            Certificate::issue(&operator, &[0u8; 32], 0, 1, extensions),
+            Err(CertificateError::Malformed("extension value too long"))
+        );
+        let extensions = vec![Extension {
+            id: 1,
+            value: vec![0u8; u16::MAX as usize],
+        }];
+        let certificate = Certificate::issue(&operator, &[0u8; 32], 0, 1, extensions).unwrap();
+        assert_eq!(
+            Certificate::from_bytes(&certificate.to_bytes()).unwrap(),
+            certificate
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use colloid::aead;
+use thiserror::Error;
+
+use crate::certificate::CertificateError;
+use crate::protocol_name::ProtocolNameError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("decryption failed")]
+    Decrypt,
+    #[error("invalid KEM ciphertext")]
+    InvalidKemCiphertext,
+    #[error("malicious public key: the DH output is all zero")]
+    MaliciousPublicKey,
+    #[error("missing {0} key")]
+    MissingKey(&'static str),
+    #[error("missing PSK {0}")]
+    MissingPsk(u8),
+    #[error("invalid handshake pattern: {0}")]
+    InvalidPattern(&'static str),
+    #[error("handshake message out of order")]
+    OutOfOrder,
+    #[error("handshake already finished")]
+    HandshakeFinished,
+    #[error("message too short")]
+    MessageTooShort,
+    #[error("message longer than 65535 bytes")]
+    MessageTooLong,
+    #[error("nonce exhausted, no more messages can be sent or received with this key")]
+    NonceExhausted,
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[error(transparent)]
+    ProtocolName(#[from] ProtocolNameError),
+}
+
+impl From<aead::Error> for Error {
+    fn from(_: aead::Error) -> Self {
+        Self::Decrypt
+    }
+}
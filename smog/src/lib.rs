@@ -1 +1,11 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod certificate;
+pub mod error;
+pub mod pipes;
+pub mod protocol_name;
 pub mod state_machines;
+
+pub use error::Error;
@@ -0,0 +1,396 @@
+//! Noise Pipes: <https://noiseprotocol.org/noise.html#noise-pipes>
+//!
+//! A client without the static key of the server runs a full `XX` handshake, then caches the key
+//! and uses `IK` on the next connections, which sends encrypted 0-RTT data with the first message.
+//! If the server can't decrypt that message (its static key changed), it switches to
+//! `XXfallback`, reusing the client's ephemeral key, and the handshake completes in 1-RTT.
+//!
+//! Each handshake message starts with a byte telling which handshake it belongs to. It is not
+//! authenticated, a tampered byte only makes the handshake fail, except that an active attacker
+//! can always force a fallback, as with any Noise Pipes implementation.
+
+use alloc::vec::Vec;
+
+use colloid::rand_core::CryptoRngCore;
+use colloid::{CipherFunction, DhFunction, HashFunction};
+
+use crate::Error;
+use crate::state_machines::handshake_state::{
+    HandshakePattern, HandshakeState, Keys, LocalKey, Modifier, RemoteKey,
+};
+use crate::state_machines::patterns;
+use crate::state_machines::transport_state::TransportState;
+
+/// The handshake a Noise Pipe completed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Path {
+    /// `XX`, the server static key was unknown.
+    Full,
+    /// `IK`, the first message carried 0-RTT data.
+    ZeroRtt,
+    /// `XXfallback`, the cached server static key was stale. The 0-RTT data was not received.
+    Fallback,
+}
+
+impl Path {
+    fn byte(self) -> u8 {
+        match self {
+            Self::Full => 0,
+            Self::ZeroRtt => 1,
+            Self::Fallback => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, Error> {
+        match byte {
+            0 => Ok(Self::Full),
+            1 => Ok(Self::ZeroRtt),
+            2 => Ok(Self::Fallback),
+            _ => Err(Error::InvalidPattern("unknown Noise Pipes handshake")),
+        }
+    }
+}
+
+fn fallback_pattern() -> Result<HandshakePattern, Error> {
+    patterns::xx().with_modifier(Modifier::Fallback)
+}
+
+// The handshake state of a driver, only missing after a failed switch of pattern.
+fn state<D: DhFunction, C: CipherFunction, H: HashFunction>(
+    state: &mut Option<HandshakeState<D, C, H>>,
+) -> Result<&mut HandshakeState<D, C, H>, Error> {
+    state.as_mut().ok_or(Error::HandshakeFinished)
+}
+
+/// The client side of a Noise Pipe.
+pub struct PipeInitiator<D: DhFunction, C: CipherFunction, H: HashFunction> {
+    state: Option<HandshakeState<D, C, H>>,
+    path: Path,
+    prologue: Vec<u8>,
+}
+
+impl<D: DhFunction, C: CipherFunction, H: HashFunction> PipeInitiator<D, C, H> {
+    /// Starts `IK` if the server static key is cached, `XX` otherwise.
+    #[cfg(feature = "getrandom")]
+    pub fn new(
+        local_key: LocalKey<D>,
+        cached_remote_static: Option<D::PublicKey>,
+        prologue: &[u8],
+    ) -> Result<Self, Error> {
+        Self::new_with_rng(
+            local_key,
+            cached_remote_static,
+            prologue,
+            colloid::rand_core::OsRng,
+        )
+    }
+
+    pub fn new_with_rng(
+        local_key: LocalKey<D>,
+        cached_remote_static: Option<D::PublicKey>,
+        prologue: &[u8],
+        rng: impl CryptoRngCore + Send + 'static,
+    ) -> Result<Self, Error> {
+        let (path, pattern) = match cached_remote_static {
+            Some(_) => (Path::ZeroRtt, patterns::ik()),
+            None => (Path::Full, patterns::xx()),
+        };
+        let keys = Keys::new(local_key, RemoteKey::new(cached_remote_static, None));
+        Ok(Self {
+            state: Some(HandshakeState::init_with_rng(
+                pattern, true, prologue, keys, rng,
+            )?),
+            path,
+            prologue: prologue.to_vec(),
+        })
+    }
+
+    /// The handshake in progress, or the completed one.
+    pub fn path(&self) -> Path {
+        self.path
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.as_ref().is_some_and(HandshakeState::is_finished)
+    }
+
+    /// The server static key, to be cached for the next `IK` handshake once finished.
+    pub fn remote_static(&self) -> Option<&D::PublicKey> {
+        self.state.as_ref()?.remote_static()
+    }
+
+    /// Appends the next handshake message to `out`, returns the path once the handshake is done.
+    pub fn write_message(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<Option<Path>, Error> {
+        let state = state(&mut self.state)?;
+        out.push(self.path.byte());
+        state.write_message(payload, out)?;
+        Ok(state.is_finished().then_some(self.path))
+    }
+
+    /// Reads the next handshake message, switching to `XXfallback` if the server asks for it.
+    /// Returns the path once the handshake is done.
+    pub fn read_message(
+        &mut self,
+        message: &[u8],
+        payload_out: &mut Vec<u8>,
+    ) -> Result<Option<Path>, Error> {
+        let (&byte, message) = message.split_first().ok_or(Error::MessageTooShort)?;
+        let path = Path::from_byte(byte)?;
+        if path != self.path {
+            // Only the answer to the first IK message may switch to the fallback.
+            if (self.path, path) != (Path::ZeroRtt, Path::Fallback)
+                || state(&mut self.state)?.message_index() != 1
+            {
+                return Err(Error::OutOfOrder);
+            }
+            let ik = self.state.take().ok_or(Error::HandshakeFinished)?;
+            self.state = Some(ik.fallback(fallback_pattern()?, &self.prologue)?);
+            self.path = Path::Fallback;
+        }
+        let state = state(&mut self.state)?;
+        state.read_message(message, payload_out)?;
+        Ok(state.is_finished().then_some(self.path))
+    }
+
+    pub fn into_transport(self) -> Result<TransportState<C>, Error> {
+        self.state.ok_or(Error::HandshakeFinished)?.into_transport()
+    }
+}
+
+/// The server side of a Noise Pipe.
+pub struct PipeResponder<D: DhFunction, C: CipherFunction, H: HashFunction> {
+    state: Option<HandshakeState<D, C, H>>,
+    path: Option<Path>,
+    prologue: Vec<u8>,
+}
+
+impl<D: DhFunction, C: CipherFunction, H: HashFunction> PipeResponder<D, C, H> {
+    #[cfg(feature = "getrandom")]
+    pub fn new(local_key: LocalKey<D>, prologue: &[u8]) -> Result<Self, Error> {
+        Self::new_with_rng(local_key, prologue, colloid::rand_core::OsRng)
+    }
+
+    /// The handshake is picked by the first message of the client.
+    pub fn new_with_rng(
+        local_key: LocalKey<D>,
+        prologue: &[u8],
+        rng: impl CryptoRngCore + Send + 'static,
+    ) -> Result<Self, Error> {
+        let keys = Keys::new(local_key, RemoteKey::default());
+        Ok(Self {
+            // Re-initialized with the pattern of the first message.
+            state: Some(HandshakeState::init_with_rng(
+                patterns::ik(),
+                false,
+                prologue,
+                keys,
+                rng,
+            )?),
+            path: None,
+            prologue: prologue.to_vec(),
+        })
+    }
+
+    /// The handshake in progress or completed, `None` before the first message.
+    pub fn path(&self) -> Option<Path> {
+        self.path
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.as_ref().is_some_and(HandshakeState::is_finished)
+    }
+
+    pub fn remote_static(&self) -> Option<&D::PublicKey> {
+        self.state.as_ref()?.remote_static()
+    }
+
+    /// Reads the next handshake message. A first `IK` message that can't be decrypted switches
+    /// the handshake to `XXfallback`, its payload is lost and `payload_out` is left untouched.
+    /// Returns the path once the handshake is done.
+    pub fn read_message(
+        &mut self,
+        message: &[u8],
+        payload_out: &mut Vec<u8>,
+    ) -> Result<Option<Path>, Error> {
+        let (&byte, message) = message.split_first().ok_or(Error::MessageTooShort)?;
+        let path = Path::from_byte(byte)?;
+        match self.path {
+            None if path == Path::Full => {
+                let ik = self.state.take().ok_or(Error::HandshakeFinished)?;
+                self.state = Some(ik.restart(patterns::xx(), &self.prologue)?);
+            }
+            None if path == Path::ZeroRtt => {}
+            Some(current) if current == path => {}
+            _ => return Err(Error::OutOfOrder),
+        }
+        self.path = Some(path);
+
+        let state = state(&mut self.state)?;
+        let first = state.message_index() == 0;
+        let mut payload = Vec::new();
+        match state.read_message(message, &mut payload) {
+            // Only the first IK message falls back, it is the one encrypted to the cached key.
+            Err(Error::Decrypt) if path == Path::ZeroRtt && first => {
+                let ik = self.state.take().ok_or(Error::HandshakeFinished)?;
+                self.state = Some(ik.fallback(fallback_pattern()?, &self.prologue)?);
+                self.path = Some(Path::Fallback);
+                Ok(None)
+            }
+            result => {
+                result?;
+                payload_out.extend(payload);
+                Ok(self.is_finished().then_some(path))
+            }
+        }
+    }
+
+    /// Appends the next handshake message to `out`, returns the path once the handshake is done.
+    pub fn write_message(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<Option<Path>, Error> {
+        let path = self.path.ok_or(Error::OutOfOrder)?;
+        let state = state(&mut self.state)?;
+        out.push(path.byte());
+        state.write_message(payload, out)?;
+        Ok(state.is_finished().then_some(path))
+    }
+
+    pub fn into_transport(self) -> Result<TransportState<C>, Error> {
+        self.state.ok_or(Error::HandshakeFinished)?.into_transport()
+    }
+}
+
+#[cfg(all(test, feature = "getrandom"))]
+mod tests {
+    use super::*;
+    use colloid::cipher::ChaChaPoly;
+    use colloid::dh::X25519;
+    use colloid::hash::Blake2s;
+
+    type Initiator = PipeInitiator<X25519, ChaChaPoly, Blake2s>;
+    type Responder = PipeResponder<X25519, ChaChaPoly, Blake2s>;
+
+    // Runs the handshake, with `payloads[i]` sent in message `i`, returns the received payloads.
+    fn run(
+        initiator: &mut Initiator,
+        responder: &mut Responder,
+        payloads: &[&[u8]],
+    ) -> (Path, Vec<Vec<u8>>) {
+        let mut received = Vec::new();
+        for (i, payload) in payloads.iter().enumerate() {
+            let mut message = Vec::new();
+            let mut payload_out = Vec::new();
+            let done = if i % 2 == 0 {
+                let written = initiator.write_message(payload, &mut message).unwrap();
+                let read = responder.read_message(&message, &mut payload_out).unwrap();
+                written.and(read)
+            } else {
+                let written = responder.write_message(payload, &mut message).unwrap();
+                let read = initiator.read_message(&message, &mut payload_out).unwrap();
+                written.and(read)
+            };
+            received.push(payload_out);
+            if let Some(path) = done {
+                return (path, received);
+            }
+        }
+        panic!("handshake not finished");
+    }
+
+    fn transport(initiator: Initiator, responder: Responder) {
+        let mut initiator = initiator.into_transport().unwrap();
+        let mut responder = responder.into_transport().unwrap();
+        let message = initiator.write_message(b"ping").unwrap();
+        assert_eq!(responder.read_message(&message).unwrap(), b"ping");
+        let message = responder.write_message(b"pong").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn full_then_zero_rtt() {
+        let server_key = LocalKey::<X25519>::new();
+        let server_static = *server_key.s().unwrap();
+        let mut server = Responder::new(server_key, b"pipes").unwrap();
+        let mut client = Initiator::new(LocalKey::new(), None, b"pipes").unwrap();
+        let (path, _) = run(&mut client, &mut server, &[b"a", b"b", b"c"]);
+        assert_eq!(path, Path::Full);
+        let cached = *client.remote_static().unwrap();
+        assert_eq!(cached, server_static);
+        transport(client, server);
+
+        let server_key = LocalKey::with_static(X25519::private_key_from_bytes(&[7; 32]).unwrap());
+        let server_static = *server_key.s().unwrap();
+        let mut server = Responder::new(server_key, b"pipes").unwrap();
+        let mut client = Initiator::new(LocalKey::new(), Some(server_static), b"pipes").unwrap();
+        let (path, received) = run(&mut client, &mut server, &[b"early data", b"b"]);
+        assert_eq!(path, Path::ZeroRtt);
+        assert_eq!(server.path(), Some(Path::ZeroRtt));
+        assert_eq!(received[0], b"early data");
+        transport(client, server);
+    }
+
+    #[test]
+    fn stale_key_falls_back() {
+        let (_, stale) = X25519::generate_keypair();
+        let server_key = LocalKey::<X25519>::new();
+        let server_static = *server_key.s().unwrap();
+        let mut server = Responder::new(server_key, b"pipes").unwrap();
+        let mut client = Initiator::new(LocalKey::new(), Some(stale), b"pipes").unwrap();
+        assert_eq!(client.path(), Path::ZeroRtt);
+        let (path, received) = run(&mut client, &mut server, &[b"early data", b"b", b"c"]);
+        assert_eq!(path, Path::Fallback);
+        assert_eq!(client.path(), Path::Fallback);
+        assert_eq!(server.path(), Some(Path::Fallback));
+        // The 0-RTT data is lost, the rest went through.
+        assert_eq!(received, [&b""[..], b"b", b"c"]);
+        assert_eq!(client.remote_static(), Some(&server_static));
+        assert!(server.remote_static().is_some());
+        transport(client, server);
+    }
+
+    #[test]
+    fn unexpected_switch() {
+        let server_key = LocalKey::<X25519>::new();
+        let mut server = Responder::new(server_key, b"pipes").unwrap();
+        let mut client = Initiator::new(LocalKey::new(), None, b"pipes").unwrap();
+        let mut message = Vec::new();
+        client.write_message(&[], &mut message).unwrap();
+        server.read_message(&message, &mut Vec::new()).unwrap();
+        let mut message = Vec::new();
+        server.write_message(&[], &mut message).unwrap();
+        // A full handshake can't switch to the fallback.
+        message[0] = Path::Fallback.byte();
+        assert!(matches!(
+            client.read_message(&message, &mut Vec::new()),
+            Err(Error::OutOfOrder)
+        ));
+        assert!(matches!(
+            server.read_message(&[7], &mut Vec::new()),
+            Err(Error::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn no_fallback_after_zero_rtt() {
+        let server_key = LocalKey::<X25519>::new();
+        let server_static = *server_key.s().unwrap();
+        let mut server = Responder::new(server_key, b"pipes").unwrap();
+        let mut client = Initiator::new(LocalKey::new(), Some(server_static), b"pipes").unwrap();
+        let (path, _) = run(&mut client, &mut server, &[b"a", b"b"]);
+        assert_eq!(path, Path::ZeroRtt);
+        // A finished IK handshake doesn't switch to the fallback anymore.
+        let message = [Path::Fallback.byte(); 100];
+        assert!(matches!(
+            client.read_message(&message, &mut Vec::new()),
+            Err(Error::OutOfOrder)
+        ));
+        assert_eq!(client.path(), Path::ZeroRtt);
+        transport(client, server);
+    }
+}
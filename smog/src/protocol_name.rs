@@ -0,0 +1,474 @@
+//! Noise protocol names, e.g. `Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s`: <https://noiseprotocol.org/noise.html#protocol-names-and-modifiers>
+//!
+//! A [`ProtocolName`] tells which handshake pattern and crypto functions a peer expects, the
+//! pattern is built with [`ProtocolName::handshake_pattern`] and the functions picked by matching
+//! on [`Dh`], [`Kem`], [`Cipher`] and [`Hash`]. Hybrid handshakes name their DH `<dh>+<kem>`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use colloid::{CipherFunction, DhFunction, HashFunction, KemFunction};
+use thiserror::Error;
+
+use crate::state_machines::handshake_state::{HandshakePattern, Modifier};
+use crate::state_machines::patterns;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProtocolNameError {
+    #[error("protocol name must start with `Noise_`")]
+    MissingPrefix,
+    #[error("protocol name must have 5 `_`-separated fields, found {0}")]
+    FieldCount(usize),
+    #[error("unknown handshake pattern `{0}`")]
+    UnknownPattern(String),
+    #[error("unknown pattern modifier `{0}`")]
+    UnknownModifier(String),
+    #[error("unknown DH function `{0}`")]
+    UnknownDh(String),
+    #[error("unknown KEM `{0}`")]
+    UnknownKem(String),
+    #[error("unknown cipher `{0}`")]
+    UnknownCipher(String),
+    #[error("unknown hash function `{0}`")]
+    UnknownHash(String),
+    #[error("`{0}` is not enabled in this build")]
+    Unsupported(&'static str),
+    #[error("invalid combination: {0}")]
+    InvalidCombination(&'static str),
+    #[error("expected {expected}, the handshake uses {found}")]
+    SuiteMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+/// DH functions, see [`colloid::dh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dh {
+    X25519,
+    X448,
+}
+
+/// KEMs of hybrid handshakes, see [`colloid::kem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kem {
+    MlKem768,
+}
+
+/// Cipher functions, see [`colloid::cipher`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    ChaChaPoly,
+    AesGcm,
+    ChaCha12Blake3,
+}
+
+/// Hash functions, see [`colloid::hash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hash {
+    Blake2s,
+    Blake2b,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Dh {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::X25519 => "25519",
+            Self::X448 => "448",
+        }
+    }
+}
+
+impl Kem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MlKem768 => "MLKEM768",
+        }
+    }
+}
+
+impl Cipher {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ChaChaPoly => "ChaChaPoly",
+            Self::AesGcm => "AESGCM",
+            Self::ChaCha12Blake3 => "ChaCha12Blake3",
+        }
+    }
+}
+
+impl Hash {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Blake2s => "BLAKE2s",
+            Self::Blake2b => "BLAKE2b",
+            Self::Sha256 => "SHA256",
+            Self::Sha512 => "SHA512",
+            Self::Blake3 => "BLAKE3",
+        }
+    }
+}
+
+impl FromStr for Dh {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "25519" => Ok(Self::X25519),
+            "448" if cfg!(feature = "x448") => Ok(Self::X448),
+            "448" => Err(ProtocolNameError::Unsupported("448")),
+            _ => Err(ProtocolNameError::UnknownDh(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for Kem {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "MLKEM768" if cfg!(feature = "hybrid") => Ok(Self::MlKem768),
+            "MLKEM768" => Err(ProtocolNameError::Unsupported("MLKEM768")),
+            _ => Err(ProtocolNameError::UnknownKem(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ChaChaPoly" if cfg!(feature = "chachapoly") => Ok(Self::ChaChaPoly),
+            "AESGCM" if cfg!(feature = "aesgcm") => Ok(Self::AesGcm),
+            // ChaCha12-BLAKE3 is std only, see colloid.
+            "ChaCha12Blake3" if cfg!(feature = "std") => Ok(Self::ChaCha12Blake3),
+            "ChaChaPoly" => Err(ProtocolNameError::Unsupported("ChaChaPoly")),
+            "AESGCM" => Err(ProtocolNameError::Unsupported("AESGCM")),
+            "ChaCha12Blake3" => Err(ProtocolNameError::Unsupported("ChaCha12Blake3")),
+            _ => Err(ProtocolNameError::UnknownCipher(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for Hash {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BLAKE2s" if cfg!(feature = "blake2") => Ok(Self::Blake2s),
+            "BLAKE2b" if cfg!(feature = "blake2") => Ok(Self::Blake2b),
+            "SHA256" if cfg!(feature = "sha2") => Ok(Self::Sha256),
+            "SHA512" if cfg!(feature = "sha2") => Ok(Self::Sha512),
+            "BLAKE3" => Ok(Self::Blake3),
+            "BLAKE2s" => Err(ProtocolNameError::Unsupported("BLAKE2s")),
+            "BLAKE2b" => Err(ProtocolNameError::Unsupported("BLAKE2b")),
+            "SHA256" => Err(ProtocolNameError::Unsupported("SHA256")),
+            "SHA512" => Err(ProtocolNameError::Unsupported("SHA512")),
+            _ => Err(ProtocolNameError::UnknownHash(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for Modifier {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fallback" => Ok(Self::Fallback),
+            "hfs" => Ok(Self::Hfs),
+            _ => s
+                .strip_prefix("psk")
+                // `u8::from_str` accepts a leading `+`.
+                .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|n| n.parse().ok())
+                .map(Self::Psk)
+                .ok_or_else(|| ProtocolNameError::UnknownModifier(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolName {
+    /// The pattern name without its modifiers, e.g. `IK`.
+    pub pattern: String,
+    pub modifiers: Vec<Modifier>,
+    pub dh: Dh,
+    /// Set for hybrid handshakes, which need the `hfs` modifier.
+    pub kem: Option<Kem>,
+    pub cipher: Cipher,
+    pub hash: Hash,
+}
+
+impl ProtocolName {
+    /// The handshake pattern, with the modifiers applied.
+    pub fn handshake_pattern(&self) -> Result<HandshakePattern, crate::Error> {
+        let mut pattern = patterns::by_name(&self.pattern)
+            .ok_or_else(|| ProtocolNameError::UnknownPattern(self.pattern.clone()))?;
+        for modifier in &self.modifiers {
+            pattern = pattern.with_modifier(*modifier)?;
+        }
+        pattern.validate()?;
+        Ok(pattern)
+    }
+
+    /// Checks the name against the functions of a handshake, use [`colloid::kem::NoKem`] as `K`
+    /// for non-hybrid handshakes.
+    pub fn check_suite<D: DhFunction, C: CipherFunction, H: HashFunction, K: KemFunction>(
+        &self,
+    ) -> Result<(), ProtocolNameError> {
+        let check = |expected: &'static str, found: &'static str| {
+            if expected != found {
+                return Err(ProtocolNameError::SuiteMismatch { expected, found });
+            }
+            Ok(())
+        };
+        check(self.dh.name(), D::NAME)?;
+        check(self.kem.map_or("", Kem::name), K::NAME)?;
+        check(self.cipher.name(), C::NAME)?;
+        check(self.hash.name(), H::NAME)
+    }
+}
+
+impl FromStr for ProtocolName {
+    type Err = ProtocolNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s
+            .strip_prefix("Noise_")
+            .ok_or(ProtocolNameError::MissingPrefix)?
+            .split('_')
+            .collect();
+        let [pattern, dh, cipher, hash] = fields[..] else {
+            return Err(ProtocolNameError::FieldCount(fields.len() + 1));
+        };
+
+        // The pattern name is upper case letters and digits (`X1K1`), the modifiers follow.
+        let split = pattern
+            .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit()))
+            .unwrap_or(pattern.len());
+        let (pattern, modifiers) = pattern.split_at(split);
+        if patterns::by_name(pattern).is_none() {
+            return Err(ProtocolNameError::UnknownPattern(pattern.to_string()));
+        }
+        let modifiers = if modifiers.is_empty() {
+            Vec::new()
+        } else {
+            modifiers
+                .split('+')
+                .map(Modifier::from_str)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if modifiers
+            .iter()
+            .enumerate()
+            .any(|(i, m)| modifiers[..i].contains(m))
+        {
+            return Err(ProtocolNameError::InvalidCombination("repeated modifier"));
+        }
+
+        let (dh, kem) = match dh.split_once('+') {
+            Some((dh, kem)) => (dh.parse()?, Some(kem.parse()?)),
+            None => (dh.parse()?, None),
+        };
+        if kem.is_some() != modifiers.contains(&Modifier::Hfs) {
+            return Err(ProtocolNameError::InvalidCombination(
+                "a KEM goes with the hfs modifier",
+            ));
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            modifiers,
+            dh,
+            kem,
+            cipher: cipher.parse()?,
+            hash: hash.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ProtocolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Noise_{}", self.pattern)?;
+        for (i, modifier) in self.modifiers.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{modifier}")?;
+        }
+        write!(f, "_{}", self.dh.name())?;
+        if let Some(kem) = self.kem {
+            write!(f, "+{}", kem.name())?;
+        }
+        write!(f, "_{}_{}", self.cipher.name(), self.hash.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(
+        feature = "std",
+        feature = "chachapoly",
+        feature = "aesgcm",
+        feature = "blake2",
+        feature = "sha2"
+    ))]
+    #[test]
+    fn round_trip() {
+        for name in [
+            "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            "Noise_IKpsk2_25519_AESGCM_SHA256",
+            "Noise_XXfallback+psk0_25519_ChaCha12Blake3_BLAKE3",
+            "Noise_N_25519_ChaChaPoly_SHA512",
+            "Noise_X1K1psk3_25519_ChaChaPoly_BLAKE2b",
+        ] {
+            let parsed: ProtocolName = name.parse().unwrap();
+            assert_eq!(parsed.to_string(), name);
+        }
+
+        let ik: ProtocolName = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(ik.pattern, "IK");
+        assert_eq!(ik.modifiers, [Modifier::Psk(2)]);
+        assert_eq!(ik.cipher, Cipher::ChaChaPoly);
+        assert_eq!(ik.hash, Hash::Blake2s);
+    }
+
+    #[cfg(feature = "chachapoly")]
+    #[test]
+    fn rejected() {
+        let err = |name: &str| name.parse::<ProtocolName>().unwrap_err();
+        assert_eq!(
+            err("Nois_XX_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::MissingPrefix
+        );
+        assert_eq!(
+            err("Noise_XX_25519_ChaChaPoly"),
+            ProtocolNameError::FieldCount(4)
+        );
+        assert!(matches!(
+            err("Noise_XY_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::UnknownPattern(_)
+        ));
+        assert!(matches!(
+            err("Noise_XXpsk_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::UnknownModifier(_)
+        ));
+        assert!(matches!(
+            err("Noise_XXpsk+1_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::UnknownModifier(_)
+        ));
+        assert!(matches!(
+            err("Noise_XX_P256_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::UnknownDh(_)
+        ));
+        assert!(matches!(
+            err("Noise_XX_25519_AESCCM_BLAKE2s"),
+            ProtocolNameError::UnknownCipher(_)
+        ));
+        assert!(matches!(
+            err("Noise_XX_25519_ChaChaPoly_MD5"),
+            ProtocolNameError::UnknownHash(_)
+        ));
+        assert!(matches!(
+            err("Noise_XXpsk0+psk0_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::InvalidCombination(_)
+        ));
+        assert!(matches!(
+            err("Noise_NNhfs_25519_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::InvalidCombination(_)
+        ));
+        #[cfg(not(feature = "x448"))]
+        assert_eq!(
+            err("Noise_XX_448_ChaChaPoly_BLAKE2s"),
+            ProtocolNameError::Unsupported("448")
+        );
+    }
+
+    #[test]
+    fn disabled_suites() {
+        let cipher = |name: &str, cipher: Cipher, enabled: bool| {
+            let expected = if enabled {
+                Ok(cipher)
+            } else {
+                Err(ProtocolNameError::Unsupported(cipher.name()))
+            };
+            assert_eq!(name.parse::<Cipher>(), expected);
+        };
+        cipher(
+            "ChaChaPoly",
+            Cipher::ChaChaPoly,
+            cfg!(feature = "chachapoly"),
+        );
+        cipher("AESGCM", Cipher::AesGcm, cfg!(feature = "aesgcm"));
+        cipher(
+            "ChaCha12Blake3",
+            Cipher::ChaCha12Blake3,
+            cfg!(feature = "std"),
+        );
+        let hash = |name: &str, hash: Hash, enabled: bool| {
+            let expected = if enabled {
+                Ok(hash)
+            } else {
+                Err(ProtocolNameError::Unsupported(hash.name()))
+            };
+            assert_eq!(name.parse::<Hash>(), expected);
+        };
+        hash("BLAKE2s", Hash::Blake2s, cfg!(feature = "blake2"));
+        hash("BLAKE2b", Hash::Blake2b, cfg!(feature = "blake2"));
+        hash("SHA256", Hash::Sha256, cfg!(feature = "sha2"));
+        hash("SHA512", Hash::Sha512, cfg!(feature = "sha2"));
+        hash("BLAKE3", Hash::Blake3, true);
+    }
+
+    #[cfg(all(feature = "chachapoly", feature = "blake2", feature = "sha2"))]
+    #[test]
+    fn handshake_pattern_and_suite() {
+        use colloid::cipher::ChaChaPoly;
+        use colloid::dh::X25519;
+        use colloid::hash::{Blake2s, Sha256};
+        use colloid::kem::NoKem;
+
+        let xx: ProtocolName = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(xx.handshake_pattern().unwrap(), patterns::xx());
+        let ik: ProtocolName = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(ik.handshake_pattern().unwrap().name(), "IKpsk2");
+        xx.check_suite::<X25519, ChaChaPoly, Blake2s, NoKem>()
+            .unwrap();
+        assert_eq!(
+            xx.check_suite::<X25519, ChaChaPoly, Sha256, NoKem>(),
+            Err(ProtocolNameError::SuiteMismatch {
+                expected: "BLAKE2s",
+                found: "SHA256"
+            })
+        );
+    }
+
+    #[cfg(all(feature = "hybrid", feature = "chachapoly", feature = "blake2"))]
+    #[test]
+    fn hybrid() {
+        use colloid::cipher::ChaChaPoly;
+        use colloid::dh::X25519;
+        use colloid::hash::Blake2s;
+
+        let name: ProtocolName = "Noise_NNhfs_25519+MLKEM768_ChaChaPoly_BLAKE2s"
+            .parse()
+            .unwrap();
+        assert_eq!(name.kem, Some(Kem::MlKem768));
+        assert_eq!(
+            name.to_string(),
+            "Noise_NNhfs_25519+MLKEM768_ChaChaPoly_BLAKE2s"
+        );
+        let pattern = name.handshake_pattern().unwrap();
+        assert_eq!(pattern.name(), "NNhfs");
+        name.check_suite::<X25519, ChaChaPoly, Blake2s, colloid::kem::MlKem768>()
+            .unwrap();
+    }
+}
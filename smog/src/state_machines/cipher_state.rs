@@ -1,96 +1,110 @@
 //! Cipehr State Machine based on The Noise Protocol spec: <https://noiseprotocol.org/noise.html#the-cipherstate-object>
 
-use chacha12_blake3::ChaCha12Blake3;
-use colloid::cipher::Cipher;
+use alloc::vec::Vec;
 
-pub struct CipherState {
-    k: [u8; 32],
-    n: [u8; 32],
-    pub cipher_obj: Cipher,
+use colloid::{CipherFunction, SecretKey, aead};
+
+use crate::Error;
+
+pub struct CipherState<C: CipherFunction> {
+    cipher: Option<C>,
+    n: u64,
+}
+
+impl<C: CipherFunction> Default for CipherState<C> {
+    fn default() -> Self {
+        Self { cipher: None, n: 0 }
+    }
 }
 
-impl CipherState {
-    pub fn init(k: [u8; 32]) -> Self {
+impl<C: CipherFunction> CipherState<C> {
+    pub fn init(k: SecretKey) -> Self {
         Self {
-            k,
-            n: [0u8; 32],
-            cipher_obj: Cipher {
-                inner: ChaCha12Blake3::new(k),
-            },
+            cipher: Some(C::new(k)),
+            n: 0,
         }
     }
 
-    pub fn init_key(&mut self, key: [u8; 32]) {
-        self.k = key;
-        self.n = [0u8; 32];
+    pub fn init_key(&mut self, key: SecretKey) {
+        self.cipher = Some(C::new(key));
+        self.n = 0;
     }
 
     pub fn has_key(&self) -> bool {
-        !self.k.is_empty()
+        self.cipher.is_some()
     }
 
-    pub fn set_nonce(&mut self, nonce: [u8; 32]) {
+    pub fn set_nonce(&mut self, nonce: u64) {
         self.n = nonce;
     }
 
-    pub(crate) fn increase_nonce_le(&mut self) {
-        let mut carry = 1u8;
-        for b in self.n.iter_mut() {
-            let (val, new_carry) = b.overflowing_add(carry);
-            *b = val;
-            carry = new_carry as u8;
-            if carry == 0 {
-                break;
+    pub fn nonce(&self) -> u64 {
+        self.n
+    }
+
+    // 2^64-1 is reserved for REKEY, so the state is exhausted once n gets there instead of
+    // wrapping around to a nonce that was already used.
+    fn cipher(&self) -> Result<Option<&C>, Error> {
+        match &self.cipher {
+            Some(_) if self.n == u64::MAX => Err(Error::NonceExhausted),
+            cipher => Ok(cipher.as_ref()),
+        }
+    }
+
+    // Returns the plaintext as it is if k is empty.
+    pub fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.cipher()? {
+            Some(cipher) => {
+                let ciphertext = cipher.encrypt(self.n, ad, plaintext);
+                self.n += 1;
+                Ok(ciphertext)
+            }
+            None => Ok(plaintext.to_vec()),
+        }
+    }
+
+    // The nonce is only increased if decryption succeeds.
+    pub fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.cipher()? {
+            Some(cipher) => {
+                let plaintext = cipher.decrypt(self.n, ad, ciphertext)?;
+                self.n += 1;
+                Ok(plaintext)
             }
+            None => Ok(ciphertext.to_vec()),
         }
     }
 
-    // pub(crate) fn decrease_nonce_le(&mut self) {
-    //     let mut borrow = 1u8;
-    //     for b in self.n.iter_mut() {
-    //         let (val, under) = b.overflowing_sub(borrow);
-    //         *b = val;
-    //         borrow = under as u8; // 1 if we underflowed, 0 otherwise
-    //         if borrow == 0 {
-    //             break; // no further borrowing needed
-    //         }
-    //     }
-    // }
-
-    pub fn encrypt_with_ad(
+    /// In-place variant of `encrypt_with_ad`, the tag is appended to `buffer`.
+    pub fn encrypt_with_ad_in_place(
         &mut self,
         ad: &[u8],
-        buf: &[u8],
-    ) -> std::result::Result<(), chacha12_blake3::Error> {
-        if self.has_key() {
-            self.increase_nonce_le();
-            self.cipher_obj.encrypt(self.n, ad, buf);
+        buffer: &mut dyn aead::Buffer,
+    ) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher()? {
+            cipher.encrypt_in_place(self.n, ad, buffer)?;
+            self.n += 1;
         }
         Ok(())
     }
 
-    pub fn decrypt_with_ad(
+    /// In-place variant of `decrypt_with_ad`, the tag is stripped from `buffer`.
+    pub fn decrypt_with_ad_in_place(
         &mut self,
         ad: &[u8],
-        buf: &[u8],
-    ) -> std::result::Result<Vec<u8>, chacha12_blake3::Error> {
-        if self.has_key() {
-            self.increase_nonce_le();
-            return self.cipher_obj.decrypt(self.n, ad, buf);
-            // match result {
-            //     Ok(v) => return Ok(v),
-            //     Err(_e) => {
-            //         self.decrement_nonce_le();
-            //         return Err(chacha12_blake3::Error {});
-            //     }
-            // }
-        } else {
-            return Err(chacha12_blake3::Error {});
+        buffer: &mut dyn aead::Buffer,
+    ) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher()? {
+            cipher.decrypt_in_place(self.n, ad, buffer)?;
+            self.n += 1;
         }
+        Ok(())
     }
 
-    pub fn rekey(&mut self) -> Result<(), chacha12_blake3::Error> {
-        self.cipher_obj.rekey(self.k);
-        Ok(())
+    /// REKEY(k): replaces the key with `ENCRYPT(k, 2^64-1, zerolen, zeros)`, n is left as it is.
+    pub fn rekey(&mut self) {
+        if let Some(cipher) = &mut self.cipher {
+            cipher.rekey();
+        }
     }
 }
@@ -1,87 +1,218 @@
 //! Handshake State Machine based on The Noise Protocol spec: <https://noiseprotocol.org/noise.html#the-handshakestate-object>
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use colloid::rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use colloid::{CipherFunction, DhFunction, HashFunction, KemFunction, SecretKey, kem::NoKem};
+
+use crate::Error;
 use crate::state_machines::symmetric_state::SymmetricState;
-use colloid::dh::{ephemeral_key, static_key};
-use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use crate::state_machines::transport_state::TransportState;
 
-pub struct LocalKey {
-    s: (StaticSecret, PublicKey),    // Local static keypair
-    e: (EphemeralSecret, PublicKey), // Local ephemeral keypair
+/// The largest Noise message, handshake or transport.
+pub const MAX_MESSAGE_LEN: usize = 65535;
+
+pub struct LocalKey<D: DhFunction, K: KemFunction = NoKem> {
+    s: Option<(D::PrivateKey, D::PublicKey)>, // Local static keypair
+    e: Option<(D::PrivateKey, D::PublicKey)>, // Local ephemeral keypair, generated by the handshake
+    s_kem: Option<(K::SecretKey, K::PublicKey)>, // Local static KEM keypair (hybrid only)
+    e_kem: Option<(K::SecretKey, K::PublicKey)>, // Local ephemeral KEM keypair (hybrid only)
 }
 
-impl LocalKey {
+impl<D: DhFunction, K: KemFunction> Default for LocalKey<D, K> {
+    fn default() -> Self {
+        Self {
+            s: None,
+            e: None,
+            s_kem: None,
+            e_kem: None,
+        }
+    }
+}
+
+impl<D: DhFunction, K: KemFunction> LocalKey<D, K> {
+    /// Local keys with a freshly generated static keypair.
+    #[cfg(feature = "getrandom")]
     pub fn new() -> Self {
-        let s = static_key::generate_keypair();
-        let e = ephemeral_key::generate_keypair();
-        Self { s, e }
+        Self::new_with_rng(&mut colloid::rand_core::OsRng)
+    }
+
+    /// Generates the static keypair from `rng`.
+    pub fn new_with_rng(rng: &mut (impl RngCore + CryptoRng + ?Sized)) -> Self {
+        Self {
+            s: Some(D::generate_keypair_with_rng(rng)),
+            ..Self::default()
+        }
+    }
+
+    /// Local keys with an existing static keypair, e.g. loaded from a key file.
+    pub fn with_static(s: D::PrivateKey) -> Self {
+        let mut local_key = Self::default();
+        local_key.set_s(s);
+        local_key
+    }
+
+    pub fn set_s(&mut self, s: D::PrivateKey) {
+        let pub_s = D::public_key(&s);
+        self.s = Some((s, pub_s));
+    }
+
+    /// Sets the ephemeral keypair instead of generating it during the handshake, only meant for
+    /// test vectors.
+    pub fn set_e(&mut self, e: D::PrivateKey) {
+        let pub_e = D::public_key(&e);
+        self.e = Some((e, pub_e));
+    }
+
+    pub fn set_s_kem(&mut self, s_kem: (K::SecretKey, K::PublicKey)) {
+        self.s_kem = Some(s_kem);
     }
 
-    pub fn set_s(&mut self, s: StaticSecret) {
-        let pub_s = PublicKey::from(&s);
-        self.s = (s, pub_s);
+    pub fn set_e_kem(&mut self, e_kem: (K::SecretKey, K::PublicKey)) {
+        self.e_kem = Some(e_kem);
     }
 
-    pub fn set_e(&mut self, e: EphemeralSecret) {
-        let pub_e = PublicKey::from(&e);
-        self.e = (e, pub_e);
+    pub fn s(&self) -> Option<&D::PublicKey> {
+        self.s.as_ref().map(|(_, public_key)| public_key)
     }
 }
 
-pub struct RemoteKey {
-    s: (Option<StaticSecret>, Option<PublicKey>), // Remote static public key
-    e: (Option<EphemeralSecret>, Option<PublicKey>), // Remote ephemeral public key
+pub struct RemoteKey<D: DhFunction, K: KemFunction = NoKem> {
+    s: Option<D::PublicKey>,     // Remote static public key
+    e: Option<D::PublicKey>,     // Remote ephemeral public key
+    s_kem: Option<K::PublicKey>, // Remote static KEM public key (hybrid only)
+    e_kem: Option<K::PublicKey>, // Remote ephemeral KEM public key (hybrid only)
 }
 
-impl RemoteKey {
-    pub fn new(s: Option<StaticSecret>, e: Option<EphemeralSecret>) -> Self {
+impl<D: DhFunction, K: KemFunction> Default for RemoteKey<D, K> {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl<D: DhFunction, K: KemFunction> RemoteKey<D, K> {
+    pub fn new(s: Option<D::PublicKey>, e: Option<D::PublicKey>) -> Self {
         Self {
-            s: (s, None),
-            e: (e, None),
+            s,
+            e,
+            s_kem: None,
+            e_kem: None,
         }
     }
 
-    pub fn set_s(&mut self, s: StaticSecret) {
-        self.s.0 = Some(s);
+    pub fn set_s(&mut self, s: D::PublicKey) {
+        self.s = Some(s);
+    }
+
+    pub fn set_e(&mut self, e: D::PublicKey) {
+        self.e = Some(e);
+    }
+
+    pub fn set_s_kem(&mut self, s_kem: K::PublicKey) {
+        self.s_kem = Some(s_kem);
     }
 
-    pub fn set_e(&mut self, e: EphemeralSecret) {
-        self.e.0 = Some(e);
+    pub fn set_e_kem(&mut self, e_kem: K::PublicKey) {
+        self.e_kem = Some(e_kem);
     }
 
     pub fn has_s(&self) -> bool {
-        self.s.0.is_some()
+        self.s.is_some()
     }
 
     pub fn has_e(&self) -> bool {
-        self.e.0.is_some()
+        self.e.is_some()
+    }
+
+    pub fn s(&self) -> Option<&D::PublicKey> {
+        self.s.as_ref()
     }
 }
 
-pub struct Keys {
-    local_key: LocalKey,
-    remote_key: RemoteKey,
+pub struct Keys<D: DhFunction, K: KemFunction = NoKem> {
+    local_key: LocalKey<D, K>,
+    remote_key: RemoteKey<D, K>,
+    psks: Vec<(u8, SecretKey)>,
 }
 
-impl Keys {
-    pub fn new(
-        locals: (StaticSecret, EphemeralSecret),
-        remotes: (Option<StaticSecret>, Option<EphemeralSecret>),
-    ) -> Self {
-        let local_s_pub = PublicKey::from(&locals.0);
-        let local_e_pub = PublicKey::from(&locals.1);
+impl<D: DhFunction, K: KemFunction> Default for Keys<D, K> {
+    fn default() -> Self {
+        Self::new(LocalKey::default(), RemoteKey::default())
+    }
+}
+
+impl<D: DhFunction, K: KemFunction> Keys<D, K> {
+    pub fn new(local_key: LocalKey<D, K>, remote_key: RemoteKey<D, K>) -> Self {
         Self {
-            local_key: LocalKey {
-                s: (locals.0, local_s_pub),
-                e: (locals.1, local_e_pub),
-            },
-            remote_key: RemoteKey {
-                s: (remotes.0, None),
-                e: (remotes.1, None),
-            },
+            local_key,
+            remote_key,
+            psks: Vec::new(),
         }
     }
+
+    /// Sets the pre-shared key used by the `psk{index}` tokens.
+    pub fn set_psk(&mut self, index: u8, psk: SecretKey) {
+        self.psks.retain(|(i, _)| *i != index);
+        self.psks.push((index, psk));
+    }
+
+    pub fn with_psk(mut self, index: u8, psk: SecretKey) -> Self {
+        self.set_psk(index, psk);
+        self
+    }
+
+    fn psk(&self, index: u8) -> Result<&SecretKey, Error> {
+        self.psks
+            .iter()
+            .find(|(i, _)| *i == index)
+            .map(|(_, psk)| psk)
+            .ok_or(Error::MissingPsk(index))
+    }
+
+    // A public key of a pre-message, ours or the peer's.
+    fn pre_message_key(&self, key: PubKey, local: bool) -> Result<&D::PublicKey, Error> {
+        let public_key = match (key, local) {
+            (PubKey::Ephemeral, true) => self.local_key.e.as_ref().map(|(_, p)| p),
+            (PubKey::Static, true) => self.local_key.s(),
+            (PubKey::Ephemeral, false) => self.remote_key.e.as_ref(),
+            (PubKey::Static, false) => self.remote_key.s(),
+        };
+        public_key.ok_or(Error::MissingKey(match (key, local) {
+            (PubKey::Ephemeral, true) => "local ephemeral",
+            (PubKey::Static, true) => "local static",
+            (PubKey::Ephemeral, false) => "remote ephemeral",
+            (PubKey::Static, false) => "remote static",
+        }))
+    }
+
+    fn pre_message_kem_key(&self, key: PubKey, local: bool) -> Result<&K::PublicKey, Error> {
+        let public_key = match (key, local) {
+            (PubKey::Ephemeral, true) => self.local_key.e_kem.as_ref().map(|(_, p)| p),
+            (PubKey::Static, true) => self.local_key.s_kem.as_ref().map(|(_, p)| p),
+            (PubKey::Ephemeral, false) => self.remote_key.e_kem.as_ref(),
+            (PubKey::Static, false) => self.remote_key.s_kem.as_ref(),
+        };
+        public_key.ok_or(Error::MissingKey(if local {
+            "local KEM"
+        } else {
+            "remote KEM"
+        }))
+    }
+
+    pub fn local_key_mut(&mut self) -> &mut LocalKey<D, K> {
+        &mut self.local_key
+    }
+
+    pub fn remote_key_mut(&mut self) -> &mut RemoteKey<D, K> {
+        &mut self.remote_key
+    }
 }
 
+/// In hybrid handshakes the `E` and `S` tokens carry the KEM public key right after the DH public
+/// key whenever the other party sends a matching `Ekem` or `Skem` token.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Tokens {
     // SND/RCVD
@@ -94,36 +225,1151 @@ pub enum Tokens {
     Ss, // s & s.
     // Pre-shared symmetric key.
     Psk(u8),
+    // KEM Operations (PQNoise), the shared secret goes through MixKey.
+    Ekem, // Encapsulate to the remote ephemeral KEM key.
+    Skem, // Encapsulate to the remote static KEM key.
+}
+
+/// A pattern modifier, written after the pattern name in protocol names, e.g. `psk0` in `XXpsk0`
+/// or `hfs` in `NNhfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    /// Mixes the PSK `n` into the handshake.
+    Psk(u8),
+    /// Turns a pattern started by the initiator into one started by the responder (Noise Pipes).
+    Fallback,
+    /// Hybrid forward secrecy: a KEM encapsulation to the remote ephemeral KEM key after `ee`.
+    Hfs,
+}
+
+impl core::fmt::Display for Modifier {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Psk(n) => write!(f, "psk{n}"),
+            Self::Fallback => f.write_str("fallback"),
+            Self::Hfs => f.write_str("hfs"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MessagePattern(Vec<Tokens>);
 
+impl MessagePattern {
+    pub fn new(tokens: Vec<Tokens>) -> Self {
+        Self(tokens)
+    }
+
+    pub fn tokens(&self) -> &[Tokens] {
+        &self.0
+    }
+}
+
+/// A public key known before the handshake starts, see [`PreMessagePattern`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PubKey {
+    Ephemeral,
     Static,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The pre-messages of a handshake pattern: the public keys each party knows about the other one
+/// before the first message, hashed in this order (initiator first) by both parties.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct PreMessagePattern {
-    pub responder_static: Option<PubKey>,
+    pub initiator: Vec<PubKey>,
+    pub responder: Vec<PubKey>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakePattern {
-    name: &'static str,
+    name: String,
+    modifiers: Vec<Modifier>,
     pre: PreMessagePattern,
     messages: Vec<(bool, MessagePattern)>,
 }
 
-pub struct HandshakeState {
-    symmetric_state: SymmetricState,
-    keys: Keys,
+impl HandshakePattern {
+    /// `messages` holds each message pattern with `true` if it is sent by the initiator.
+    pub fn new(
+        name: impl Into<String>,
+        pre: PreMessagePattern,
+        messages: Vec<(bool, MessagePattern)>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            modifiers: Vec::new(),
+            pre,
+            messages,
+        }
+    }
+
+    /// The pattern name with its modifiers, e.g. `XXpsk0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn modifiers(&self) -> &[Modifier] {
+        &self.modifiers
+    }
+
+    /// Applies `modifier` to the pattern and appends it to the name, `+`-separated after the first
+    /// one.
+    pub fn with_modifier(mut self, modifier: Modifier) -> Result<Self, Error> {
+        if self.modifiers.contains(&modifier) {
+            return Err(Error::InvalidPattern("modifier applied more than once"));
+        }
+        match modifier {
+            // `psk0` goes at the start of the first message, `pskN` at the end of the Nth one.
+            Modifier::Psk(0) => {
+                let (_, pattern) = self
+                    .messages
+                    .first_mut()
+                    .ok_or(Error::InvalidPattern("no message patterns"))?;
+                pattern.0.insert(0, Tokens::Psk(0));
+            }
+            Modifier::Psk(n) => {
+                let (_, pattern) = self
+                    .messages
+                    .get_mut(n as usize - 1)
+                    .ok_or(Error::InvalidPattern("psk modifier after the last message"))?;
+                pattern.0.push(Tokens::Psk(n));
+            }
+            // The first message of the initiator becomes a pre-message, so the responder sends
+            // first.
+            Modifier::Fallback => {
+                if self.messages.len() < 2 || !self.messages[0].0 {
+                    return Err(Error::InvalidPattern(
+                        "fallback needs an interactive pattern started by the initiator",
+                    ));
+                }
+                let (_, first) = self.messages.remove(0);
+                for token in first.0 {
+                    self.pre.initiator.push(match token {
+                        Tokens::E => PubKey::Ephemeral,
+                        Tokens::S => PubKey::Static,
+                        _ => {
+                            return Err(Error::InvalidPattern(
+                                "fallback needs a first message of public keys only",
+                            ));
+                        }
+                    });
+                }
+            }
+            Modifier::Hfs => {
+                let (_, pattern) = self
+                    .messages
+                    .iter_mut()
+                    .find(|(_, pattern)| pattern.0.contains(&Tokens::Ee))
+                    .ok_or(Error::InvalidPattern("hfs needs a pattern with ee"))?;
+                let ee = pattern.0.iter().position(|t| *t == Tokens::Ee).unwrap();
+                pattern.0.insert(ee + 1, Tokens::Ekem);
+            }
+        }
+        if !self.modifiers.is_empty() {
+            self.name.push('+');
+        }
+        self.name.push_str(&modifier.to_string());
+        self.modifiers.push(modifier);
+        Ok(self)
+    }
+
+    pub fn pre_messages(&self) -> &PreMessagePattern {
+        &self.pre
+    }
+
+    pub fn messages(&self) -> &[(bool, MessagePattern)] {
+        &self.messages
+    }
+
+    /// Checks the pattern against the validity rules of the spec (section 7.3):
+    ///
+    /// 1. DHs and KEM encapsulations only use keys that were sent before, pre-messages included.
+    /// 2. A party sends its ephemeral and static public keys at most once.
+    /// 3. After a DH with its static key, a party doesn't encrypt a payload until it also did the
+    ///    DH between its ephemeral key and the same remote key.
+    /// 4. After a `psk` token, a party doesn't encrypt anything until it sent its ephemeral key.
+    ///
+    /// Messages must also alternate between the parties, and a DH can't be repeated.
+    pub fn validate(&self) -> Result<(), Error> {
+        // Whether the (initiator, responder) ephemeral and static keys have been sent.
+        let mut e = [false; 2];
+        let mut s = [false; 2];
+        let send = |sent: &mut [bool; 2], party: usize| {
+            if core::mem::replace(&mut sent[party], true) {
+                return Err(Error::InvalidPattern("a public key is sent more than once"));
+            }
+            Ok(())
+        };
+        for (party, keys) in [(0, &self.pre.initiator), (1, &self.pre.responder)] {
+            for key in keys {
+                match key {
+                    PubKey::Ephemeral => send(&mut e, party)?,
+                    PubKey::Static => send(&mut s, party)?,
+                }
+            }
+        }
+
+        if self.messages.is_empty() {
+            return Err(Error::InvalidPattern("no message patterns"));
+        }
+        let mut dhs = Vec::new();
+        let mut psk = false;
+        let mut senders = [false; 2];
+        let mut previous = None;
+        for (by_initiator, pattern) in &self.messages {
+            if previous == Some(*by_initiator) {
+                return Err(Error::InvalidPattern("messages must alternate"));
+            }
+            previous = Some(*by_initiator);
+            let (party, other) = if *by_initiator { (0, 1) } else { (1, 0) };
+            senders[party] = true;
+            for &token in pattern.tokens() {
+                match token {
+                    Tokens::E => send(&mut e, party)?,
+                    Tokens::S => {
+                        check_key_security(&dhs, *by_initiator)?;
+                        check_psk_security(psk, e[party])?;
+                        send(&mut s, party)?
+                    }
+                    Tokens::Ee | Tokens::Es | Tokens::Se | Tokens::Ss => {
+                        let known = match token {
+                            Tokens::Ee => e[0] && e[1],
+                            Tokens::Es => e[0] && s[1],
+                            Tokens::Se => s[0] && e[1],
+                            _ => s[0] && s[1],
+                        };
+                        if !known {
+                            return Err(Error::InvalidPattern("DH with a key that was not sent"));
+                        }
+                        if dhs.contains(&token) {
+                            return Err(Error::InvalidPattern("DH performed more than once"));
+                        }
+                        dhs.push(token);
+                    }
+                    Tokens::Ekem if !e[other] => {
+                        return Err(Error::InvalidPattern(
+                            "KEM encapsulation to a key that was not sent",
+                        ));
+                    }
+                    Tokens::Skem if !s[other] => {
+                        return Err(Error::InvalidPattern(
+                            "KEM encapsulation to a key that was not sent",
+                        ));
+                    }
+                    Tokens::Psk(_) => psk = true,
+                    Tokens::Ekem | Tokens::Skem => {}
+                }
+            }
+            check_key_security(&dhs, *by_initiator)?;
+            check_psk_security(psk, e[party])?;
+        }
+        // Transport messages, only sent by parties that send handshake messages.
+        for (party, sends) in senders.into_iter().enumerate() {
+            if sends {
+                check_key_security(&dhs, party == 0)?;
+                check_psk_security(psk, e[party])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn has_token(&self, token: Tokens) -> bool {
+        self.messages
+            .iter()
+            .any(|(_, pattern)| pattern.0.contains(&token))
+    }
+
+    fn has_psk(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|(_, pattern)| pattern.0.iter().any(|t| matches!(t, Tokens::Psk(_))))
+    }
+
+    // Whether the `E`/`S` public keys sent by `initiator` carry a KEM key: only if the other party
+    // sends the matching `Ekem`/`Skem` token.
+    fn carries_kem(&self, kem_token: Tokens, initiator: bool) -> bool {
+        self.messages.iter().any(|(by_initiator, pattern)| {
+            *by_initiator != initiator && pattern.0.contains(&kem_token)
+        })
+    }
+}
+
+// Validity rule 4: PSK handshakes rely on the ephemeral key (mixed with MixKey) for nonce
+// uniqueness, so encrypting without it would reuse the PSK-derived key.
+fn check_psk_security(psk: bool, sent_e: bool) -> Result<(), Error> {
+    if psk && !sent_e {
+        return Err(Error::InvalidPattern(
+            "data encrypted after a psk token without an ephemeral key",
+        ));
+    }
+    Ok(())
+}
+
+// Validity rule 3, for a party about to encrypt a payload after the DHs in `dhs`.
+fn check_key_security(dhs: &[Tokens], initiator: bool) -> Result<(), Error> {
+    let done = |token| dhs.contains(&token);
+    let secure = if initiator {
+        (!done(Tokens::Se) || done(Tokens::Ee)) && (!done(Tokens::Ss) || done(Tokens::Es))
+    } else {
+        (!done(Tokens::Es) || done(Tokens::Ee)) && (!done(Tokens::Ss) || done(Tokens::Se))
+    };
+    if !secure {
+        return Err(Error::InvalidPattern(
+            "payload encrypted after a static DH without the ephemeral DH",
+        ));
+    }
+    Ok(())
+}
+
+pub struct HandshakeState<D: DhFunction, C: CipherFunction, H: HashFunction, K: KemFunction = NoKem>
+{
+    symmetric_state: SymmetricState<C, H>,
+    keys: Keys<D, K>,
     initiator: bool,
-    message_pattern: MessagePattern,
+    pattern: HandshakePattern,
+    // Index of the next message in `pattern`.
+    message_index: usize,
+    // Source of ephemeral keys and KEM encapsulations.
+    rng: Box<dyn CryptoRngCore + Send>,
+}
+
+fn take<'a>(message: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if message.len() < len {
+        return Err(Error::MessageTooShort);
+    }
+    let (head, tail) = message.split_at(len);
+    *message = tail;
+    Ok(head)
 }
 
-impl HandshakeState {
-    pub fn init(handshake_pattern: HandshakePattern, initiator: bool, prologue: &[u8], keys: Keys) {
+impl<D: DhFunction, C: CipherFunction, H: HashFunction, K: KemFunction> HandshakeState<D, C, H, K> {
+    #[cfg(feature = "getrandom")]
+    pub fn init(
+        handshake_pattern: HandshakePattern,
+        initiator: bool,
+        prologue: &[u8],
+        keys: Keys<D, K>,
+    ) -> Result<Self, Error> {
+        Self::init_with_rng(
+            handshake_pattern,
+            initiator,
+            prologue,
+            keys,
+            colloid::rand_core::OsRng,
+        )
+    }
+
+    /// Like `init`, with the randomness of the handshake (ephemeral keys, KEM encapsulations)
+    /// drawn from `rng`. Two handshakes with the same keys and seeded RNGs are identical.
+    pub fn init_with_rng(
+        handshake_pattern: HandshakePattern,
+        initiator: bool,
+        prologue: &[u8],
+        keys: Keys<D, K>,
+        rng: impl CryptoRngCore + Send + 'static,
+    ) -> Result<Self, Error> {
+        Self::init_boxed(handshake_pattern, initiator, prologue, keys, Box::new(rng))
+    }
+
+    fn init_boxed(
+        handshake_pattern: HandshakePattern,
+        initiator: bool,
+        prologue: &[u8],
+        keys: Keys<D, K>,
+        rng: Box<dyn CryptoRngCore + Send>,
+    ) -> Result<Self, Error> {
+        if K::NAME.is_empty()
+            && (handshake_pattern.has_token(Tokens::Ekem)
+                || handshake_pattern.has_token(Tokens::Skem))
+        {
+            return Err(Error::InvalidPattern("KEM tokens need a KEM function"));
+        }
+        handshake_pattern.validate()?;
+
+        // Hybrid handshakes name their DH as `<dh>+<kem>`.
+        let dh_name = if K::NAME.is_empty() {
+            D::NAME.to_string()
+        } else {
+            format!("{}+{}", D::NAME, K::NAME)
+        };
+        let protocol_name = format!(
+            "Noise_{}_{}_{}_{}",
+            handshake_pattern.name,
+            dh_name,
+            C::NAME,
+            H::NAME
+        );
+        let mut symmetric_state = SymmetricState::init(&protocol_name);
+        symmetric_state.mix_hash(prologue);
+
+        for (by_initiator, pre) in [
+            (true, &handshake_pattern.pre.initiator),
+            (false, &handshake_pattern.pre.responder),
+        ] {
+            let local = by_initiator == initiator;
+            for &key in pre {
+                let public_key = keys.pre_message_key(key, local)?;
+                symmetric_state.mix_hash(public_key.as_ref());
+                if key == PubKey::Ephemeral && handshake_pattern.has_psk() {
+                    symmetric_state.mix_key(public_key.as_ref());
+                }
+                let kem_token = match key {
+                    PubKey::Ephemeral => Tokens::Ekem,
+                    PubKey::Static => Tokens::Skem,
+                };
+                if handshake_pattern.carries_kem(kem_token, by_initiator) {
+                    let kem_key = keys.pre_message_kem_key(key, local)?;
+                    symmetric_state.mix_hash(kem_key.as_ref());
+                }
+            }
+        }
+
+        Ok(Self {
+            symmetric_state,
+            keys,
+            initiator,
+            pattern: handshake_pattern,
+            message_index: 0,
+            rng,
+        })
+    }
+
+    /// Restarts a failed handshake with a pattern carrying the `fallback` modifier, e.g.
+    /// `XXfallback` after the responder couldn't decrypt the first `IK` message. The keys, PSKs
+    /// and RNG are kept, the initiator's ephemeral key becomes a pre-message, and the remote
+    /// static key (stale for the initiator) is forgotten.
+    pub fn fallback(self, pattern: HandshakePattern, prologue: &[u8]) -> Result<Self, Error> {
+        if !pattern.modifiers().contains(&Modifier::Fallback) {
+            return Err(Error::InvalidPattern("not a fallback pattern"));
+        }
+        self.restart(pattern, prologue)
+    }
+
+    // Starts over with another pattern, see `fallback`.
+    pub(crate) fn restart(self, pattern: HandshakePattern, prologue: &[u8]) -> Result<Self, Error> {
+        let mut keys = self.keys;
+        keys.remote_key.s = None;
+        keys.remote_key.s_kem = None;
+        if !self.initiator {
+            keys.local_key.e = None;
+            keys.local_key.e_kem = None;
+        }
+        Self::init_boxed(pattern, self.initiator, prologue, keys, self.rng)
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Whether every message of the pattern has been written or read.
+    pub fn is_finished(&self) -> bool {
+        self.message_index == self.pattern.messages.len()
+    }
+
+    // Index of the next message in the pattern.
+    pub(crate) fn message_index(&self) -> usize {
+        self.message_index
+    }
+
+    /// Whether the next message is ours to write.
+    pub fn is_my_turn(&self) -> bool {
+        self.pattern
+            .messages
+            .get(self.message_index)
+            .is_some_and(|(by_initiator, _)| *by_initiator == self.initiator)
+    }
+
+    /// Sets the PSK of the `psk{index}` token, which can be done during the handshake, e.g. by an
+    /// `IKpsk2` responder picking the PSK of the initiator whose static key it just received.
+    pub fn set_psk(&mut self, index: u8, psk: SecretKey) {
+        self.keys.set_psk(index, psk);
+    }
+
+    /// The static public key of the peer, known after it was received or from a pre-message.
+    pub fn remote_static(&self) -> Option<&D::PublicKey> {
+        self.keys.remote_key.s()
+    }
+
+    pub fn get_handshake_hash(&self) -> H::Output {
+        self.symmetric_state.get_handshake_hash()
+    }
+
+    /// The transport phase of a finished handshake, with the send and receive CipherStates picked
+    /// by role and the keying-material exporter.
+    pub fn into_transport(self) -> Result<TransportState<C>, Error> {
+        if !self.is_finished() {
+            return Err(Error::OutOfOrder);
+        }
+        Ok(TransportState::new(
+            self.initiator,
+            self.symmetric_state.split(),
+            self.symmetric_state.exporter(),
+        ))
+    }
+
+    // The tokens of the next message, if it goes in the given direction.
+    fn next_tokens(&self, writing: bool) -> Result<Vec<Tokens>, Error> {
+        let (by_initiator, pattern) = self
+            .pattern
+            .messages
+            .get(self.message_index)
+            .ok_or(Error::HandshakeFinished)?;
+        if (*by_initiator == self.initiator) != writing {
+            return Err(Error::OutOfOrder);
+        }
+        // Checked before the message is processed, so a missing PSK leaves the state untouched
+        // and can still be set.
+        for token in &pattern.0 {
+            if let Tokens::Psk(index) = token {
+                self.keys.psk(*index)?;
+            }
+        }
+        Ok(pattern.0.clone())
+    }
+
+    fn tag_len(&self) -> usize {
+        if self.symmetric_state.has_key() {
+            C::TAGLEN
+        } else {
+            0
+        }
+    }
+
+    // The length of the message written for `tokens` and a payload of `payload_len` bytes,
+    // following whether the cipher state has a key at each token like `write_message` does.
+    fn message_len(&self, tokens: &[Tokens], payload_len: usize) -> usize {
+        let mut has_key = self.symmetric_state.has_key();
+        let tag_len = |has_key: bool| if has_key { C::TAGLEN } else { 0 };
+        let mut len = 0;
+        for token in tokens {
+            match token {
+                Tokens::E => {
+                    len += D::DHLEN;
+                    if self.pattern.carries_kem(Tokens::Ekem, self.initiator) {
+                        len += K::PUBLICKEYLEN;
+                    }
+                    has_key |= self.pattern.has_psk();
+                }
+                Tokens::S => {
+                    len += D::DHLEN + tag_len(has_key);
+                    if self.pattern.carries_kem(Tokens::Skem, self.initiator) {
+                        len += K::PUBLICKEYLEN + tag_len(has_key);
+                    }
+                }
+                Tokens::Ee | Tokens::Es | Tokens::Se | Tokens::Ss | Tokens::Psk(_) => {
+                    has_key = true;
+                }
+                Tokens::Ekem | Tokens::Skem => {
+                    len += K::CIPHERTEXTLEN + tag_len(has_key);
+                    has_key = true;
+                }
+            }
+        }
+        len + payload_len + tag_len(has_key)
+    }
+
+    fn local_static(&self) -> Result<&(D::PrivateKey, D::PublicKey), Error> {
+        self.keys
+            .local_key
+            .s
+            .as_ref()
+            .ok_or(Error::MissingKey("local static"))
+    }
+
+    // ee, es, se and ss.
+    fn mix_dh(&mut self, token: Tokens) -> Result<(), Error> {
+        let local = &self.keys.local_key;
+        let remote = &self.keys.remote_key;
+        let local_e = || local.e.as_ref().ok_or(Error::MissingKey("local ephemeral"));
+        let local_s = || local.s.as_ref().ok_or(Error::MissingKey("local static"));
+        let remote_e = || {
+            remote
+                .e
+                .as_ref()
+                .ok_or(Error::MissingKey("remote ephemeral"))
+        };
+        let remote_s = || remote.s.as_ref().ok_or(Error::MissingKey("remote static"));
+        // The first letter is the initiator's key, the second one the responder's.
+        let (private_key, public_key) = match (token, self.initiator) {
+            (Tokens::Ee, _) => (&local_e()?.0, remote_e()?),
+            (Tokens::Es, true) | (Tokens::Se, false) => (&local_e()?.0, remote_s()?),
+            (Tokens::Es, false) | (Tokens::Se, true) => (&local_s()?.0, remote_e()?),
+            (Tokens::Ss, _) => (&local_s()?.0, remote_s()?),
+            _ => unreachable!("not a DH token"),
+        };
+        self.symmetric_state.mix_dh::<D>(private_key, public_key)
+    }
+
+    /// WriteMessage(payload, message_buffer): appends the next handshake message to `out`. Once
+    /// [`is_finished`](Self::is_finished), [`into_transport`](Self::into_transport) gives the
+    /// transport phase.
+    pub fn write_message(&mut self, payload: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        let tokens = self.next_tokens(true)?;
+        // Rejected before anything is mixed in, so the handshake can go on with a shorter payload.
+        let len = self.message_len(&tokens, payload.len());
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong);
+        }
+        let start = out.len();
+        for token in tokens {
+            match token {
+                Tokens::E => {
+                    if self.keys.local_key.e.is_none() {
+                        self.keys.local_key.e = Some(D::generate_keypair_with_rng(&mut *self.rng));
+                    }
+                    let (_, e) = self.keys.local_key.e.as_ref().unwrap();
+                    out.extend_from_slice(e.as_ref());
+                    self.symmetric_state.mix_hash(e.as_ref());
+                    if self.pattern.has_psk() {
+                        self.symmetric_state.mix_key(e.as_ref());
+                    }
+                    if self.pattern.carries_kem(Tokens::Ekem, self.initiator) {
+                        if self.keys.local_key.e_kem.is_none() {
+                            self.keys.local_key.e_kem =
+                                Some(K::generate_keypair_with_rng(&mut *self.rng));
+                        }
+                        let (_, e_kem) = self.keys.local_key.e_kem.as_ref().unwrap();
+                        out.extend_from_slice(e_kem.as_ref());
+                        self.symmetric_state.mix_hash(e_kem.as_ref());
+                    }
+                }
+                Tokens::S => {
+                    let s = self.local_static()?.1.clone();
+                    out.extend(self.symmetric_state.encrypt_and_hash(s.as_ref())?);
+                    if self.pattern.carries_kem(Tokens::Skem, self.initiator) {
+                        let (_, s_kem) = self
+                            .keys
+                            .local_key
+                            .s_kem
+                            .as_ref()
+                            .ok_or(Error::MissingKey("local static KEM"))?;
+                        out.extend(self.symmetric_state.encrypt_and_hash(s_kem.as_ref())?);
+                    }
+                }
+                Tokens::Ee | Tokens::Es | Tokens::Se | Tokens::Ss => self.mix_dh(token)?,
+                Tokens::Psk(index) => {
+                    let psk = self.keys.psk(index)?;
+                    self.symmetric_state.mix_key_and_hash(psk.expose());
+                }
+                Tokens::Ekem | Tokens::Skem => {
+                    let remote_key = &self.keys.remote_key;
+                    let public_key = if token == Tokens::Ekem {
+                        remote_key.e_kem.as_ref()
+                    } else {
+                        remote_key.s_kem.as_ref()
+                    }
+                    .ok_or(Error::MissingKey("remote KEM"))?;
+                    out.extend(
+                        self.symmetric_state
+                            .encapsulate_and_mix_key::<K>(&mut *self.rng, public_key)?,
+                    );
+                }
+            }
+        }
+        out.extend(self.symmetric_state.encrypt_and_hash(payload)?);
+        debug_assert_eq!(out.len() - start, len);
+        self.message_index += 1;
+        Ok(())
+    }
+
+    /// ReadMessage(message, payload_buffer): processes the next handshake message and appends its
+    /// payload to `payload_out`, like `write_message`.
+    pub fn read_message(&mut self, message: &[u8], payload_out: &mut Vec<u8>) -> Result<(), Error> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong);
+        }
+        let mut message = message;
+        for token in self.next_tokens(false)? {
+            match token {
+                Tokens::E => {
+                    let bytes = take(&mut message, D::DHLEN)?;
+                    let re = D::public_key_from_bytes(bytes).ok_or(Error::MessageTooShort)?;
+                    self.symmetric_state.mix_hash(bytes);
+                    if self.pattern.has_psk() {
+                        self.symmetric_state.mix_key(bytes);
+                    }
+                    self.keys.remote_key.e = Some(re);
+                    if self.pattern.carries_kem(Tokens::Ekem, !self.initiator) {
+                        let bytes = take(&mut message, K::PUBLICKEYLEN)?;
+                        let re_kem =
+                            K::public_key_from_bytes(bytes).ok_or(Error::MessageTooShort)?;
+                        self.symmetric_state.mix_hash(bytes);
+                        self.keys.remote_key.e_kem = Some(re_kem);
+                    }
+                }
+                Tokens::S => {
+                    let bytes = take(&mut message, D::DHLEN + self.tag_len())?;
+                    let rs = self.symmetric_state.decrypt_and_hash(bytes)?;
+                    self.keys.remote_key.s =
+                        Some(D::public_key_from_bytes(&rs).ok_or(Error::MessageTooShort)?);
+                    if self.pattern.carries_kem(Tokens::Skem, !self.initiator) {
+                        let bytes = take(&mut message, K::PUBLICKEYLEN + self.tag_len())?;
+                        let rs_kem = self.symmetric_state.decrypt_and_hash(bytes)?;
+                        self.keys.remote_key.s_kem =
+                            Some(K::public_key_from_bytes(&rs_kem).ok_or(Error::MessageTooShort)?);
+                    }
+                }
+                Tokens::Ee | Tokens::Es | Tokens::Se | Tokens::Ss => self.mix_dh(token)?,
+                Tokens::Psk(index) => {
+                    let psk = self.keys.psk(index)?;
+                    self.symmetric_state.mix_key_and_hash(psk.expose());
+                }
+                Tokens::Ekem | Tokens::Skem => {
+                    let bytes = take(&mut message, K::CIPHERTEXTLEN + self.tag_len())?;
+                    let local_key = &self.keys.local_key;
+                    let (secret_key, _) = if token == Tokens::Ekem {
+                        local_key.e_kem.as_ref()
+                    } else {
+                        local_key.s_kem.as_ref()
+                    }
+                    .ok_or(Error::MissingKey("local KEM"))?;
+                    self.symmetric_state
+                        .decapsulate_and_mix_key::<K>(secret_key, bytes)?;
+                }
+            }
+        }
+        payload_out.extend(self.symmetric_state.decrypt_and_hash(message)?);
+        self.message_index += 1;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "getrandom"))]
+mod tests {
+    use super::*;
+    use crate::state_machines::patterns::{self, alternating};
+    use alloc::vec;
+    use colloid::cipher::ChaChaPoly;
+    use colloid::dh::X25519;
+    use colloid::hash::Blake2s;
+    use rand_chacha::{ChaCha20Rng, rand_core::SeedableRng};
+
+    type State = HandshakeState<X25519, ChaChaPoly, Blake2s>;
+
+    // Runs the handshake to completion and checks both sides end up with the same keys.
+    fn handshake<D: DhFunction, K: KemFunction>(
+        mut initiator: HandshakeState<D, ChaChaPoly, Blake2s, K>,
+        mut responder: HandshakeState<D, ChaChaPoly, Blake2s, K>,
+    ) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let (mut writer, mut reader) = (&mut initiator, &mut responder);
+        if !writer.is_my_turn() {
+            core::mem::swap(&mut writer, &mut reader);
+        }
+        while !writer.is_finished() {
+            let mut message = Vec::new();
+            writer.write_message(b"payload", &mut message).unwrap();
+            let mut payload = Vec::new();
+            reader.read_message(&message, &mut payload).unwrap();
+            assert_eq!(payload, b"payload");
+            messages.push(message);
+            core::mem::swap(&mut writer, &mut reader);
+        }
+        assert!(initiator.is_finished() && responder.is_finished());
+        assert_eq!(
+            initiator.get_handshake_hash(),
+            responder.get_handshake_hash()
+        );
+        let mut initiator = initiator.into_transport().unwrap();
+        let mut responder = responder.into_transport().unwrap();
+        let message = initiator.write_message(b"one").unwrap();
+        assert_eq!(responder.read_message(&message).unwrap(), b"one");
+        let message = responder.write_message(b"two").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"two");
+        messages
+    }
+
+    #[test]
+    fn seeded_local_keys() {
+        let a = LocalKey::<X25519>::new_with_rng(&mut ChaCha20Rng::from_seed([1; 32]));
+        let b = LocalKey::<X25519>::new_with_rng(&mut ChaCha20Rng::from_seed([1; 32]));
+        assert_eq!(a.s(), b.s());
+        assert!(a.e.is_none());
+    }
+
+    #[test]
+    fn nn_and_xx() {
+        let nn = patterns::nn();
+        let initiator = State::init(nn.clone(), true, b"prologue", Keys::default()).unwrap();
+        let responder = State::init(nn, false, b"prologue", Keys::default()).unwrap();
+        let messages = handshake(initiator, responder);
+        assert_eq!(messages[0].len(), 32 + 7);
+        assert_eq!(messages[1].len(), 32 + 7 + 16);
+
+        let xx = patterns::xx();
+        let initiator_keys = Keys::new(LocalKey::new(), RemoteKey::default());
+        let responder_keys = Keys::new(LocalKey::new(), RemoteKey::default());
+        let initiator_static = initiator_keys.local_key.s().cloned();
+        let initiator = State::init(xx.clone(), true, &[], initiator_keys).unwrap();
+        let mut responder = State::init(xx, false, &[], responder_keys).unwrap();
+        assert!(responder.remote_static().is_none());
+        let (mut writer, mut reader) = (initiator, responder);
+        let mut buf = Vec::new();
+        for _ in 0..3 {
+            let mut message = Vec::new();
+            writer.write_message(&[], &mut message).unwrap();
+            reader.read_message(&message, &mut buf).unwrap();
+            core::mem::swap(&mut writer, &mut reader);
+        }
+        responder = writer;
+        assert_eq!(responder.remote_static(), initiator_static.as_ref());
+
+        let mut initiator = reader.into_transport().unwrap();
+        let mut responder = responder.into_transport().unwrap();
+        let message = responder.write_message(b"hello").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn ik_with_psk() {
+        let ik = patterns::ik().with_modifier(Modifier::Psk(2)).unwrap();
+        assert_eq!(ik.name(), "IKpsk2");
+        let responder_local = LocalKey::new();
+        let responder_static = *responder_local.s().unwrap();
+        let mut initiator_keys = Keys::new(
+            LocalKey::new(),
+            RemoteKey::new(Some(responder_static), None),
+        );
+        let mut responder_keys = Keys::new(responder_local, RemoteKey::default());
+        initiator_keys.set_psk(2, [9u8; 32].into());
+        responder_keys.set_psk(2, [9u8; 32].into());
+        let initiator = State::init(ik.clone(), true, &[], initiator_keys).unwrap();
+        let responder = State::init(ik.clone(), false, &[], responder_keys).unwrap();
+        handshake(initiator, responder);
+
+        // The responder static key is a pre-message, so the initiator must know it.
+        assert!(matches!(
+            State::init(ik.clone(), true, &[], Keys::default()),
+            Err(Error::MissingKey(_))
+        ));
+
+        // A responder with another static key can't read the first message.
+        let initiator_keys = Keys::new(
+            LocalKey::new(),
+            RemoteKey::new(Some(responder_static), None),
+        );
+        let mut initiator = State::init(ik.clone(), true, &[], initiator_keys).unwrap();
+        let responder_keys = Keys::new(LocalKey::new(), RemoteKey::default());
+        let mut responder = State::init(ik, false, &[], responder_keys).unwrap();
+        let mut message = Vec::new();
+        initiator.write_message(&[], &mut message).unwrap();
+        assert!(matches!(
+            responder.read_message(&message, &mut Vec::new()),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn out_of_order_and_missing_psk() {
+        let nn = patterns::nn().with_modifier(Modifier::Psk(0)).unwrap();
+        let mut initiator = State::init(nn.clone(), true, &[], Keys::default()).unwrap();
+        assert!(matches!(
+            initiator.read_message(&[], &mut Vec::new()),
+            Err(Error::OutOfOrder)
+        ));
+        assert!(matches!(
+            initiator.write_message(&[], &mut Vec::new()),
+            Err(Error::MissingPsk(0))
+        ));
+        // Nothing was processed, the PSK can still be set.
+        initiator.set_psk(0, [1u8; 32].into());
+        let mut responder = State::init(nn, false, &[], Keys::default()).unwrap();
+        responder.set_psk(0, [1u8; 32].into());
+        handshake(initiator, responder);
+    }
+
+    #[test]
+    fn oversize_payload_leaves_state_untouched() {
+        let xx = patterns::xx();
+        let keys = || Keys::new(LocalKey::new(), RemoteKey::default());
+        let mut initiator = State::init(xx.clone(), true, &[], keys()).unwrap();
+        let mut responder = State::init(xx, false, &[], keys()).unwrap();
+        let mut message = Vec::new();
+        initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message, &mut Vec::new()).unwrap();
+
+        // e, ee, s (encrypted), es and the encrypted payload.
+        let max_payload = MAX_MESSAGE_LEN - 32 - (32 + 16) - 16;
+        let mut message = Vec::new();
+        assert!(matches!(
+            responder.write_message(&vec![0u8; max_payload + 1], &mut message),
+            Err(Error::MessageTooLong)
+        ));
+        assert!(message.is_empty());
+        responder
+            .write_message(&vec![0u8; max_payload], &mut message)
+            .unwrap();
+        assert_eq!(message.len(), MAX_MESSAGE_LEN);
+        initiator.read_message(&message, &mut Vec::new()).unwrap();
+        let mut message = Vec::new();
+        initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message, &mut Vec::new()).unwrap();
+        assert_eq!(
+            initiator.get_handshake_hash(),
+            responder.get_handshake_hash()
+        );
+    }
+
+    #[test]
+    fn psk_modifiers() {
+        let psk = || SecretKey::from([7u8; 32]);
+        let xx = patterns::xx()
+            .with_modifier(Modifier::Psk(0))
+            .unwrap()
+            .with_modifier(Modifier::Psk(3))
+            .unwrap();
+        assert_eq!(xx.name(), "XXpsk0+psk3");
+        assert_eq!(xx.messages()[0].1.tokens(), [Tokens::Psk(0), Tokens::E]);
+        assert_eq!(xx.messages()[2].1.tokens().last(), Some(&Tokens::Psk(3)));
+        let keys = || {
+            Keys::new(LocalKey::new(), RemoteKey::default())
+                .with_psk(0, psk())
+                .with_psk(3, psk())
+        };
+        let initiator = State::init(xx.clone(), true, &[], keys()).unwrap();
+        let responder = State::init(xx.clone(), false, &[], keys()).unwrap();
+        handshake(initiator, responder);
+
+        // Different PSKs: the first message already fails, the psk0 key encrypts its payload.
+        let mut initiator = State::init(xx.clone(), true, &[], keys()).unwrap();
+        let other = Keys::new(LocalKey::new(), RemoteKey::default())
+            .with_psk(0, [8u8; 32].into())
+            .with_psk(3, psk());
+        let mut responder = State::init(xx, false, &[], other).unwrap();
+        let mut message = Vec::new();
+        initiator.write_message(b"payload", &mut message).unwrap();
+        assert!(matches!(
+            responder.read_message(&message, &mut Vec::new()),
+            Err(Error::Decrypt)
+        ));
+
+        assert!(matches!(
+            patterns::nn().with_modifier(Modifier::Psk(3)),
+            Err(Error::InvalidPattern(_))
+        ));
+        assert!(matches!(
+            patterns::nn()
+                .with_modifier(Modifier::Psk(1))
+                .unwrap()
+                .with_modifier(Modifier::Psk(1)),
+            Err(Error::InvalidPattern(_))
+        ));
+        // A payload encrypted after the psk token, before any ephemeral key.
+        use Tokens::*;
+        assert!(
+            alternating("", &[], &[], &[&[Psk(0)], &[E], &[E, Ee]])
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn psk_set_during_handshake() {
+        // An IKpsk2 responder picks the PSK after learning the static key of the initiator.
+        let ik = patterns::ik().with_modifier(Modifier::Psk(2)).unwrap();
+        let responder_local = LocalKey::new();
+        let responder_static = *responder_local.s().unwrap();
+        let initiator_keys = Keys::new(
+            LocalKey::new(),
+            RemoteKey::new(Some(responder_static), None),
+        )
+        .with_psk(2, [5u8; 32].into());
+        let mut initiator = State::init(ik.clone(), true, &[], initiator_keys).unwrap();
+        let mut responder = State::init(
+            ik,
+            false,
+            &[],
+            Keys::new(responder_local, RemoteKey::default()),
+        )
+        .unwrap();
+
+        let mut message = Vec::new();
+        initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message, &mut Vec::new()).unwrap();
+        assert!(responder.remote_static().is_some());
+        assert!(matches!(
+            responder.write_message(&[], &mut Vec::new()),
+            Err(Error::MissingPsk(2))
+        ));
+        responder.set_psk(2, [5u8; 32].into());
+        let mut message = Vec::new();
+        responder.write_message(&[], &mut message).unwrap();
+        initiator.read_message(&message, &mut Vec::new()).unwrap();
+        assert_eq!(
+            initiator.get_handshake_hash(),
+            responder.get_handshake_hash()
+        );
+    }
+
+    #[test]
+    fn malicious_ephemeral_aborts() {
+        let nn = patterns::nn();
+        let mut initiator = State::init(nn.clone(), true, &[], Keys::default()).unwrap();
+        let mut responder = State::init(nn, false, &[], Keys::default()).unwrap();
+        let mut message = Vec::new();
+        initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message, &mut Vec::new()).unwrap();
+        // A responder sending the identity point as its ephemeral key.
+        let malicious = [0u8; 32];
+        assert!(matches!(
+            initiator.read_message(&malicious, &mut Vec::new()),
+            Err(Error::MaliciousPublicKey)
+        ));
+    }
+
+    #[test]
+    fn seeded_handshakes_are_identical() {
+        let xx = patterns::xx();
+        let run = || {
+            let mut rng = ChaCha20Rng::from_seed([3; 32]);
+            let initiator_keys = Keys::new(LocalKey::new_with_rng(&mut rng), RemoteKey::default());
+            let responder_keys = Keys::new(LocalKey::new_with_rng(&mut rng), RemoteKey::default());
+            let initiator = State::init_with_rng(
+                xx.clone(),
+                true,
+                &[],
+                initiator_keys,
+                ChaCha20Rng::from_seed([4; 32]),
+            )
+            .unwrap();
+            let responder = State::init_with_rng(
+                xx.clone(),
+                false,
+                &[],
+                responder_keys,
+                ChaCha20Rng::from_seed([5; 32]),
+            )
+            .unwrap();
+            handshake(initiator, responder)
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn hybrid_nn() {
+        use colloid::kem::MlKem768;
+        type Hybrid = HandshakeState<X25519, ChaChaPoly, Blake2s, MlKem768>;
+        let nn = patterns::nn().with_modifier(Modifier::Hfs).unwrap();
+        assert_eq!(nn.name(), "NNhfs");
+        let initiator = Hybrid::init(nn.clone(), true, &[], Keys::default()).unwrap();
+        let responder = Hybrid::init(nn.clone(), false, &[], Keys::default()).unwrap();
+        let messages = handshake(initiator, responder);
+        assert_eq!(messages[0].len(), 32 + MlKem768::PUBLICKEYLEN + 7);
+        assert_eq!(
+            messages[1].len(),
+            32 + MlKem768::CIPHERTEXTLEN + 16 + 7 + 16
+        );
+
+        assert!(matches!(
+            State::init(nn, true, &[], Keys::default()),
+            Err(Error::InvalidPattern(_))
+        ));
+    }
+
+    // Keys for both parties of `pattern`, with the static keys of the pre-messages shared.
+    fn pre_shared_keys(pattern: &HandshakePattern) -> (Keys<X25519>, Keys<X25519>) {
+        let (initiator_local, responder_local) = (LocalKey::new(), LocalKey::new());
+        let mut initiator_remote = RemoteKey::default();
+        let mut responder_remote = RemoteKey::default();
+        if pattern.pre_messages().responder.contains(&PubKey::Static) {
+            initiator_remote.set_s(*responder_local.s().unwrap());
+        }
+        if pattern.pre_messages().initiator.contains(&PubKey::Static) {
+            responder_remote.set_s(*initiator_local.s().unwrap());
+        }
+        (
+            Keys::new(initiator_local, initiator_remote),
+            Keys::new(responder_local, responder_remote),
+        )
+    }
+
+    #[test]
+    fn fundamental_and_deferred_patterns() {
+        for name in patterns::FUNDAMENTAL.into_iter().chain(patterns::DEFERRED) {
+            let pattern = patterns::by_name(name).unwrap();
+            assert_eq!(pattern.name(), name);
+            pattern.validate().unwrap();
+            let (initiator_keys, responder_keys) = pre_shared_keys(&pattern);
+            let initiator = State::init(pattern.clone(), true, &[], initiator_keys).unwrap();
+            let responder = State::init(pattern, false, &[], responder_keys).unwrap();
+            handshake(initiator, responder);
+        }
+        assert!(patterns::by_name("XY").is_none());
+    }
+
+    #[test]
+    fn ephemeral_pre_message() {
+        use Tokens::*;
+        // XXfallback: the responder answers to the ephemeral key of an aborted handshake.
+        let pre = PreMessagePattern {
+            initiator: vec![PubKey::Ephemeral],
+            responder: Vec::new(),
+        };
+        let messages = vec![
+            (false, MessagePattern::new(vec![E, Ee, S, Es])),
+            (true, MessagePattern::new(vec![S, Se])),
+        ];
+        let fallback = patterns::xx().with_modifier(Modifier::Fallback).unwrap();
+        assert_eq!(fallback.name(), "XXfallback");
+        assert_eq!(fallback.pre_messages(), &pre);
+        assert_eq!(fallback.messages(), messages);
+        assert!(patterns::ik().with_modifier(Modifier::Fallback).is_err());
+        assert!(patterns::n().with_modifier(Modifier::Fallback).is_err());
+        let mut initiator_local = LocalKey::new();
+        let (e, _) = X25519::generate_keypair();
+        let e_public = X25519::public_key(&e);
+        initiator_local.set_e(e);
+        let initiator_keys = Keys::new(initiator_local, RemoteKey::default());
+        let responder_keys = Keys::new(LocalKey::new(), RemoteKey::new(None, Some(e_public)));
+        let initiator = State::init(fallback.clone(), true, &[], initiator_keys).unwrap();
+        let responder = State::init(fallback.clone(), false, &[], responder_keys).unwrap();
+        assert!(responder.is_my_turn());
+        handshake(initiator, responder);
+
+        assert!(matches!(
+            State::init(fallback, false, &[], Keys::default()),
+            Err(Error::MissingKey("remote ephemeral"))
+        ));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        use Tokens::*;
+        let invalid = |pre_initiator: &[PubKey], messages: &[&[Tokens]]| {
+            alternating("", pre_initiator, &[], messages).validate()
+        };
+        // Sending a key twice, also after a pre-message.
+        assert!(invalid(&[], &[&[E], &[E, Ee], &[E]]).is_err());
+        assert!(invalid(&[PubKey::Static], &[&[E, S]]).is_err());
+        // DH with a key that was not sent yet.
+        assert!(invalid(&[], &[&[E, Es]]).is_err());
+        assert!(invalid(&[], &[&[E], &[Ee, E]]).is_err());
+        // Repeated DH.
+        assert!(invalid(&[], &[&[E], &[E, Ee, Ee]]).is_err());
+        // Encapsulation to a key that was not sent.
+        assert!(invalid(&[], &[&[E], &[E, Skem]]).is_err());
+        // The responder encrypts with only `es`, the initiator with only `se`.
+        assert!(invalid(&[], &[&[E, S], &[E, Es]]).is_err());
+        assert!(invalid(&[], &[&[E], &[E, S], &[S, Se]]).is_err());
+        // The initiator encrypts `s` after `se` but before `ee`.
+        assert!(matches!(
+            invalid(&[PubKey::Static], &[&[E], &[E], &[Se, S]]),
+            Err(Error::InvalidPattern(
+                "payload encrypted after a static DH without the ephemeral DH"
+            ))
+        ));
+        // Messages in the same direction.
+        let pattern = HandshakePattern::new(
+            "",
+            PreMessagePattern::default(),
+            vec![
+                (true, MessagePattern::new(vec![E])),
+                (true, MessagePattern::new(vec![S])),
+            ],
+        );
+        assert!(matches!(
+            State::init(pattern, true, &[], Keys::default()),
+            Err(Error::InvalidPattern("messages must alternate"))
+        ));
     }
 }
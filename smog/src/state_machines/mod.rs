@@ -1,3 +1,5 @@
 pub mod cipher_state;
 pub mod handshake_state;
+pub mod patterns;
 pub mod symmetric_state;
+pub mod transport_state;
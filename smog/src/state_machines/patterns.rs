@@ -0,0 +1,241 @@
+//! The fundamental handshake patterns of the Noise spec: <https://noiseprotocol.org/noise.html#handshake-patterns>
+//!
+//! One-way patterns (`N`, `K`, `X`) only have a message from the initiator. The first letter of
+//! an interactive pattern tells how the initiator's static key reaches the responder, the second
+//! one the responder's: `N` no static key, `K` known beforehand (a pre-message), `X` transmitted
+//! during the handshake, `I` transmitted immediately with the first message.
+//!
+//! The deferred patterns postpone the DH (or DHs) authenticating a party by one message, marked by
+//! a `1` after its letter, e.g. `NK1`: <https://noiseprotocol.org/noise.html#deferred-handshake-patterns>
+
+use alloc::vec::Vec;
+
+use crate::state_machines::handshake_state::{
+    HandshakePattern, MessagePattern, PreMessagePattern, PubKey, Tokens,
+};
+
+use PubKey::Static;
+use Tokens::*;
+
+/// The names of the fundamental patterns, all of them are returned by [`by_name`].
+pub const FUNDAMENTAL: [&str; 15] = [
+    "N", "K", "X", "NN", "NK", "NX", "KN", "KK", "KX", "XN", "XK", "XX", "IN", "IK", "IX",
+];
+
+/// The names of the deferred patterns, all of them are returned by [`by_name`].
+pub const DEFERRED: [&str; 23] = [
+    "NK1", "NX1", "X1N", "X1K", "XK1", "X1K1", "X1X", "XX1", "X1X1", "K1N", "K1K", "KK1", "K1K1",
+    "K1X", "KX1", "K1X1", "I1N", "I1K", "IK1", "I1K1", "I1X", "IX1", "I1X1",
+];
+
+/// A pattern whose messages alternate, starting with the initiator.
+pub(crate) fn alternating(
+    name: &str,
+    initiator: &[PubKey],
+    responder: &[PubKey],
+    messages: &[&[Tokens]],
+) -> HandshakePattern {
+    let pre = PreMessagePattern {
+        initiator: initiator.to_vec(),
+        responder: responder.to_vec(),
+    };
+    let messages = messages
+        .iter()
+        .enumerate()
+        .map(|(i, tokens)| (i % 2 == 0, MessagePattern::new(tokens.to_vec())))
+        .collect::<Vec<_>>();
+    HandshakePattern::new(name, pre, messages)
+}
+
+/// The fundamental or deferred pattern called `name`, e.g. `"XX"` or `"X1K1"`.
+pub fn by_name(name: &str) -> Option<HandshakePattern> {
+    Some(match name {
+        "N" => n(),
+        "K" => k(),
+        "X" => x(),
+        "NN" => nn(),
+        "NK" => nk(),
+        "NX" => nx(),
+        "KN" => kn(),
+        "KK" => kk(),
+        "KX" => kx(),
+        "XN" => xn(),
+        "XK" => xk(),
+        "XX" => xx(),
+        "IN" => in_(),
+        "IK" => ik(),
+        "IX" => ix(),
+        _ => return deferred(name),
+    })
+}
+
+/// ```text
+/// <- s
+/// ...
+/// -> e, es
+/// ```
+pub fn n() -> HandshakePattern {
+    alternating("N", &[], &[Static], &[&[E, Es]])
+}
+
+/// ```text
+/// -> s
+/// <- s
+/// ...
+/// -> e, es, ss
+/// ```
+pub fn k() -> HandshakePattern {
+    alternating("K", &[Static], &[Static], &[&[E, Es, Ss]])
+}
+
+/// ```text
+/// <- s
+/// ...
+/// -> e, es, s, ss
+/// ```
+pub fn x() -> HandshakePattern {
+    alternating("X", &[], &[Static], &[&[E, Es, S, Ss]])
+}
+
+/// ```text
+/// -> e
+/// <- e, ee
+/// ```
+pub fn nn() -> HandshakePattern {
+    alternating("NN", &[], &[], &[&[E], &[E, Ee]])
+}
+
+/// ```text
+/// <- s
+/// ...
+/// -> e, es
+/// <- e, ee
+/// ```
+pub fn nk() -> HandshakePattern {
+    alternating("NK", &[], &[Static], &[&[E, Es], &[E, Ee]])
+}
+
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// ```
+pub fn nx() -> HandshakePattern {
+    alternating("NX", &[], &[], &[&[E], &[E, Ee, S, Es]])
+}
+
+/// ```text
+/// -> s
+/// ...
+/// -> e
+/// <- e, ee, se
+/// ```
+pub fn kn() -> HandshakePattern {
+    alternating("KN", &[Static], &[], &[&[E], &[E, Ee, Se]])
+}
+
+/// ```text
+/// -> s
+/// <- s
+/// ...
+/// -> e, es, ss
+/// <- e, ee, se
+/// ```
+pub fn kk() -> HandshakePattern {
+    alternating("KK", &[Static], &[Static], &[&[E, Es, Ss], &[E, Ee, Se]])
+}
+
+/// ```text
+/// -> s
+/// ...
+/// -> e
+/// <- e, ee, se, s, es
+/// ```
+pub fn kx() -> HandshakePattern {
+    alternating("KX", &[Static], &[], &[&[E], &[E, Ee, Se, S, Es]])
+}
+
+/// ```text
+/// -> e
+/// <- e, ee
+/// -> s, se
+/// ```
+pub fn xn() -> HandshakePattern {
+    alternating("XN", &[], &[], &[&[E], &[E, Ee], &[S, Se]])
+}
+
+/// ```text
+/// <- s
+/// ...
+/// -> e, es
+/// <- e, ee
+/// -> s, se
+/// ```
+pub fn xk() -> HandshakePattern {
+    alternating("XK", &[], &[Static], &[&[E, Es], &[E, Ee], &[S, Se]])
+}
+
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// -> s, se
+/// ```
+pub fn xx() -> HandshakePattern {
+    alternating("XX", &[], &[], &[&[E], &[E, Ee, S, Es], &[S, Se]])
+}
+
+/// ```text
+/// -> e, s
+/// <- e, ee, se
+/// ```
+pub fn in_() -> HandshakePattern {
+    alternating("IN", &[], &[], &[&[E, S], &[E, Ee, Se]])
+}
+
+/// ```text
+/// <- s
+/// ...
+/// -> e, es, s, ss
+/// <- e, ee, se
+/// ```
+pub fn ik() -> HandshakePattern {
+    alternating("IK", &[], &[Static], &[&[E, Es, S, Ss], &[E, Ee, Se]])
+}
+
+/// ```text
+/// -> e, s
+/// <- e, ee, se, s, es
+/// ```
+pub fn ix() -> HandshakePattern {
+    alternating("IX", &[], &[], &[&[E, S], &[E, Ee, Se, S, Es]])
+}
+
+// The deferred patterns, in the order and notation of the spec.
+fn deferred(name: &str) -> Option<HandshakePattern> {
+    let (initiator, responder, messages): (&[PubKey], &[PubKey], &[&[Tokens]]) = match name {
+        "NK1" => (&[], &[Static], &[&[E], &[E, Ee, Es]]),
+        "NX1" => (&[], &[], &[&[E], &[E, Ee, S], &[Es]]),
+        "X1N" => (&[], &[], &[&[E], &[E, Ee], &[S], &[Se]]),
+        "X1K" => (&[], &[Static], &[&[E, Es], &[E, Ee], &[S], &[Se]]),
+        "XK1" => (&[], &[Static], &[&[E], &[E, Ee, Es], &[S, Se]]),
+        "X1K1" => (&[], &[Static], &[&[E], &[E, Ee, Es], &[S], &[Se]]),
+        "X1X" => (&[], &[], &[&[E], &[E, Ee, S, Es], &[S], &[Se]]),
+        "XX1" => (&[], &[], &[&[E], &[E, Ee, S], &[Es, S, Se]]),
+        "X1X1" => (&[], &[], &[&[E], &[E, Ee, S], &[Es, S], &[Se]]),
+        "K1N" => (&[Static], &[], &[&[E], &[E, Ee], &[Se]]),
+        "K1K" => (&[Static], &[Static], &[&[E, Es], &[E, Ee], &[Se]]),
+        "KK1" => (&[Static], &[Static], &[&[E], &[E, Ee, Se, Es]]),
+        "K1K1" => (&[Static], &[Static], &[&[E], &[E, Ee, Es], &[Se]]),
+        "K1X" => (&[Static], &[], &[&[E], &[E, Ee, S, Es], &[Se]]),
+        "KX1" => (&[Static], &[], &[&[E], &[E, Ee, Se, S], &[Es]]),
+        "K1X1" => (&[Static], &[], &[&[E], &[E, Ee, S], &[Se, Es]]),
+        "I1N" => (&[], &[], &[&[E, S], &[E, Ee], &[Se]]),
+        "I1K" => (&[], &[Static], &[&[E, Es, S], &[E, Ee], &[Se]]),
+        "IK1" => (&[], &[Static], &[&[E, S], &[E, Ee, Se, Es]]),
+        "I1K1" => (&[], &[Static], &[&[E, S], &[E, Ee, Es], &[Se]]),
+        "I1X" => (&[], &[], &[&[E, S], &[E, Ee, S, Es], &[Se]]),
+        "IX1" => (&[], &[], &[&[E, S], &[E, Ee, Se, S], &[Es]]),
+        "I1X1" => (&[], &[], &[&[E, S], &[E, Ee, S], &[Se, Es]]),
+        _ => return None,
+    };
+    Some(alternating(name, initiator, responder, messages))
+}
@@ -6,101 +6,204 @@
 //! > uses to encrypt and decrypt ciphertexts. During the handshake phase each party has a single CipherState, but during
 //! > the transport phase each party has two CipherState objects: one for sending, and one for receiving.
 
-// use bytes::BytesMut;
-use colloid::{dh, hash};
+use alloc::vec::Vec;
 
+use colloid::{
+    ByteArray, CipherFunction, DhFunction, HashFunction, KemFunction, Secret, SecretKey,
+};
+
+use colloid::rand_core::{CryptoRng, RngCore};
+
+use crate::Error;
 use crate::state_machines::cipher_state::CipherState;
+use crate::state_machines::transport_state::Exporter;
+
+pub struct SymmetricState<C: CipherFunction, H: HashFunction> {
+    cipher_state: CipherState<C>,
+    ck: Secret<H::Output>,
+    h: H::Output,
+}
 
-pub struct SymmetricState {
-    cipher_state: CipherState,
-    ck: [u8; hash::HASHLEN],
-    h: [u8; hash::HASHLEN],
+// Cipher keys are 32 bytes, so a 64-byte HASHLEN output gets truncated.
+fn cipher_key(output: &[u8]) -> SecretKey {
+    let mut k = SecretKey::new([0u8; 32]);
+    k.expose_mut().copy_from_slice(&output[..32]);
+    k
 }
 
-impl SymmetricState {
-    pub fn init(&mut self, protocol_name: &str) {
-        if protocol_name.len() == hash::HASHLEN {
-            self.h = protocol_name
-                .as_bytes()
-                .try_into()
-                .expect("Error parsing protocol name.");
+impl<C: CipherFunction, H: HashFunction> SymmetricState<C, H> {
+    pub fn init(protocol_name: &str) -> Self {
+        let protocol_name = protocol_name.as_bytes();
+        let h = if protocol_name.len() <= H::HASHLEN {
+            let mut h = H::Output::zeroed();
+            h.as_mut()[..protocol_name.len()].copy_from_slice(protocol_name);
+            h
         } else {
-            self.h = *hash::once::rayon::hash(protocol_name.as_bytes()).as_bytes()
+            H::hash(protocol_name)
+        };
+
+        Self {
+            cipher_state: CipherState::default(),
+            ck: Secret::new(h.clone()),
+            h,
         }
+    }
 
-        self.ck = self.h;
-        let k: [u8; 32] = rand::random();
-        self.cipher_state = CipherState::init(k);
-        self.cipher_state.init_key([0u8; hash::HASHLEN]);
+    pub fn mix_key(&mut self, input_key_material: &[u8]) {
+        let [chaining_key, temp_k] = H::hkdf(self.ck.expose(), input_key_material);
+        self.ck = chaining_key;
+        self.cipher_state.init_key(cipher_key(temp_k.as_ref()));
     }
 
-    pub fn mix_key(&mut self, input_key_material: &[u8; dh::DHLEN]) {
-        let chaining_key: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        let temp_k: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        let buf3: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        hash::once::rayon::hkdf(&self.ck, input_key_material, 2, chaining_key, temp_k, buf3);
-        self.ck = *chaining_key;
-        self.cipher_state.init_key(*temp_k);
+    pub fn has_key(&self) -> bool {
+        self.cipher_state.has_key()
     }
 
     pub fn mix_hash(&mut self, data: &[u8]) {
-        let result: &[u8] = &[self.h, data.try_into().expect("Error parsing data.")].concat();
-        self.h = *hash::once::rayon::hash(result).as_bytes();
+        self.h = H::hash_many(&[self.h.as_ref(), data]);
     }
 
     // Handling psk.
-    pub fn mix_key_and_hash(&mut self, input_key_material: &[u8; dh::DHLEN]) {
-        let chaining_key: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        let temp_h: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        let temp_k: &mut [u8; hash::HASHLEN] = &mut [0u8; hash::HASHLEN];
-        hash::once::rayon::hkdf(
-            &self.ck,
-            input_key_material,
-            3,
-            chaining_key,
-            temp_h,
-            temp_k,
-        );
-        self.mix_hash(temp_h);
-        self.cipher_state.init_key(*temp_k);
+    pub fn mix_key_and_hash(&mut self, input_key_material: &[u8]) {
+        let [chaining_key, temp_h, temp_k] = H::hkdf(self.ck.expose(), input_key_material);
+        self.ck = chaining_key;
+        self.mix_hash(temp_h.as_ref());
+        self.cipher_state.init_key(cipher_key(temp_k.as_ref()));
     }
 
     /// This functionn should only be called at the end of a handshake (See the Noise Protocol spec
     /// for more details).
-    pub fn get_handshake_hash(&self) -> [u8; 32] {
-        self.h
+    pub fn get_handshake_hash(&self) -> H::Output {
+        self.h.clone()
     }
 
-    // If k is empty, function decrypt_with_ad will set self.h to plaintext
+    // If k is empty, the plaintext is returned as it is.
     // buf means plaintext, got ciphertext in return.
-    pub fn encrypt_and_hash(
-        &mut self,
-        buf: &'static [u8],
-    ) -> Result<&'static [u8], chacha12_blake3::Error> {
-        self.cipher_state.decrypt_with_ad(&self.h, buf)?;
-        Ok(buf)
+    pub fn encrypt_and_hash(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        let ciphertext = self.cipher_state.encrypt_with_ad(self.h.as_ref(), buf)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
     }
 
     // buf means ciphertext, got plaintext in return.
-    pub fn decrypt_and_hash(
+    pub fn decrypt_and_hash(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = self.cipher_state.decrypt_with_ad(self.h.as_ref(), buf)?;
+        self.mix_hash(buf);
+        Ok(plaintext)
+    }
+
+    /// Runs DH with a public key received from the peer and mixes the output into the chaining
+    /// key. The handshake has to be aborted on [`Error::MaliciousPublicKey`], which is returned
+    /// for a low-order public key instead of mixing in an all-zero output.
+    pub fn mix_dh<D: DhFunction>(
+        &mut self,
+        private_key: &D::PrivateKey,
+        remote_public_key: &D::PublicKey,
+    ) -> Result<(), Error> {
+        let shared_secret =
+            D::dh_checked(private_key, remote_public_key).map_err(|_| Error::MaliciousPublicKey)?;
+        self.mix_key(shared_secret.as_ref());
+        Ok(())
+    }
+
+    /// The sending side of the `ekem`/`skem` tokens: encapsulates to the remote KEM public key,
+    /// mixes the shared secret into the chaining key and returns the (encrypted) KEM ciphertext.
+    pub fn encapsulate_and_mix_key<K: KemFunction>(
+        &mut self,
+        rng: &mut (impl RngCore + CryptoRng + ?Sized),
+        remote_public_key: &K::PublicKey,
+    ) -> Result<Vec<u8>, Error> {
+        let (ciphertext, shared_secret) = K::encapsulate_with_rng(rng, remote_public_key);
+        let ciphertext = self.encrypt_and_hash(ciphertext.as_ref())?;
+        self.mix_key(shared_secret.as_ref());
+        Ok(ciphertext)
+    }
+
+    /// The receiving side of the `ekem`/`skem` tokens.
+    pub fn decapsulate_and_mix_key<K: KemFunction>(
         &mut self,
-        buf: &'static [u8],
-    ) -> Result<&'static [u8], chacha12_blake3::Error> {
-        self.cipher_state.encrypt_with_ad(&self.h, buf)?;
-        Ok(buf)
+        secret_key: &K::SecretKey,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let ciphertext = self.decrypt_and_hash(buf)?;
+        let ciphertext =
+            K::ciphertext_from_bytes(&ciphertext).ok_or(Error::InvalidKemCiphertext)?;
+        let shared_secret =
+            K::decapsulate(secret_key, &ciphertext).ok_or(Error::InvalidKemCiphertext)?;
+        self.mix_key(shared_secret.as_ref());
+        Ok(())
     }
 
-    pub fn split(&mut self) -> (CipherState, CipherState) {
-        let temp_k1 = &mut [0u8; 32];
-        let temp_k2 = &mut [0u8; 32];
-        let buf3 = &mut [0u8; 32];
-        hash::once::rayon::hkdf(&self.ck, &[0u8; 0], 2, temp_k1, temp_k2, buf3);
-        let k1: [u8; 32] = rand::random();
-        let k2: [u8; 32] = rand::random();
-        let mut c1 = CipherState::init(k1);
-        let mut c2 = CipherState::init(k2);
-        c1.init_key(*temp_k1);
-        c2.init_key(*temp_k2);
+    /// The keying-material exporter of the transport phase, call at the end of the handshake.
+    pub fn exporter(&self) -> Exporter {
+        Exporter::new(self.ck.expose().as_ref(), self.h.as_ref())
+    }
+
+    /// Split(): the pair `(c1, c2)` of the transport phase, the initiator sends with `c1`.
+    /// `HandshakeState::into_transport` picks them by role.
+    pub fn split(&self) -> (CipherState<C>, CipherState<C>) {
+        let [temp_k1, temp_k2] = H::hkdf(self.ck.expose(), &[]);
+        let c1 = CipherState::init(cipher_key(temp_k1.as_ref()));
+        let c2 = CipherState::init(cipher_key(temp_k2.as_ref()));
         (c1, c2)
     }
 }
+
+#[cfg(all(test, feature = "getrandom"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_mix_key() {
+        use crate::state_machines::transport_state::TransportState;
+        use colloid::{cipher::ChaChaPoly, dh::X25519, hash::Blake2s, kem::MlKem768};
+
+        let name = "Noise_NN_25519+MLKEM768_ChaChaPoly_BLAKE2s";
+        let mut initiator = SymmetricState::<ChaChaPoly, Blake2s>::init(name);
+        let mut responder = SymmetricState::<ChaChaPoly, Blake2s>::init(name);
+
+        let (e_i, e_i_pub) = X25519::generate_keypair();
+        let (e_r, e_r_pub) = X25519::generate_keypair();
+        initiator.mix_dh::<X25519>(&e_i, &e_r_pub).unwrap();
+        responder.mix_dh::<X25519>(&e_r, &e_i_pub).unwrap();
+
+        let (kem_secret, kem_public) = MlKem768::generate_keypair();
+        let ciphertext = responder
+            .encapsulate_and_mix_key::<MlKem768>(&mut colloid::rand_core::OsRng, &kem_public)
+            .unwrap();
+        initiator
+            .decapsulate_and_mix_key::<MlKem768>(&kem_secret, &ciphertext)
+            .unwrap();
+        assert_eq!(
+            initiator.get_handshake_hash(),
+            responder.get_handshake_hash()
+        );
+
+        let mut initiator = TransportState::new(true, initiator.split(), initiator.exporter());
+        let mut responder = TransportState::new(false, responder.split(), responder.exporter());
+        let message = initiator.write_message(b"hello").unwrap();
+        assert_eq!(responder.read_message(&message).unwrap(), b"hello");
+        let message = responder.write_message(b"world").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"world");
+
+        let (mut a, mut b) = ([0u8; 48], [0u8; 48]);
+        initiator.export_keying_material(b"channel binding", &[], &mut a);
+        responder.export_keying_material(b"channel binding", &[], &mut b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn malicious_public_key() {
+        use colloid::{cipher::ChaChaPoly, dh::X25519, hash::Blake2s};
+
+        let mut state =
+            SymmetricState::<ChaChaPoly, Blake2s>::init("Noise_NN_25519_ChaChaPoly_BLAKE2s");
+        let (e, _) = X25519::generate_keypair();
+        let low_order = X25519::public_key_from_bytes(&[0u8; 32]).unwrap();
+        assert!(matches!(
+            state.mix_dh::<X25519>(&e, &low_order),
+            Err(Error::MaliciousPublicKey)
+        ));
+        assert!(!state.cipher_state.has_key());
+    }
+}
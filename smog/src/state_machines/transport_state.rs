@@ -0,0 +1,236 @@
+//! Transport phase, after Split(): one CipherState for sending and one for receiving.
+//!
+//! Messages are limited to 65535 bytes like handshake messages, and each direction can send
+//! 2^64-1 messages before its nonce is exhausted. Rekeying is up to the application, e.g. after
+//! a number of messages, and has to be done by both peers for the same direction.
+
+use alloc::vec::Vec;
+
+use colloid::{CipherFunction, SecretKey, blake3};
+
+use crate::Error;
+use crate::state_machines::cipher_state::CipherState;
+use crate::state_machines::handshake_state::MAX_MESSAGE_LEN;
+
+// BLAKE3 derive_key contexts, see `Exporter`.
+const EXPORTER_SECRET_CONTEXT: &str = "quiclike smog 2025-06 exporter secret";
+const EXPORTER_CONTEXT: &str = "quiclike smog 2025-06 exporter";
+
+/// Derives keying material for the application from the end of a handshake, like the TLS
+/// exporter. The exporter secret is `derive_key(ck || h)`, so it is bound to the whole handshake
+/// but independent of the transport keys, and every (label, context) pair gives independent
+/// output.
+pub struct Exporter {
+    secret: SecretKey,
+}
+
+impl Exporter {
+    /// Built from the final chaining key and handshake hash.
+    pub fn new(ck: &[u8], h: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key(EXPORTER_SECRET_CONTEXT);
+        hasher.update(ck);
+        hasher.update(h);
+        let secret = SecretKey::new(hasher.finalize().into());
+        Self { secret }
+    }
+
+    /// Fills `out` with keying material for `label` and `context`.
+    pub fn export(&self, label: &[u8], context: &[u8], out: &mut [u8]) {
+        let mut hasher = blake3::Hasher::new_derive_key(EXPORTER_CONTEXT);
+        hasher.update(self.secret.expose());
+        // Length prefixes keep (label, context) pairs unambiguous.
+        hasher.update(&(label.len() as u64).to_le_bytes());
+        hasher.update(label);
+        hasher.update(&(context.len() as u64).to_le_bytes());
+        hasher.update(context);
+        hasher.update(&(out.len() as u64).to_le_bytes());
+        hasher.finalize_xof().fill(out);
+    }
+}
+
+pub struct TransportState<C: CipherFunction> {
+    send: CipherState<C>,
+    recv: CipherState<C>,
+    exporter: Exporter,
+    initiator: bool,
+}
+
+impl<C: CipherFunction> TransportState<C> {
+    /// `ciphers` is the pair returned by Split(), the initiator sends with the first one.
+    pub(crate) fn new(
+        initiator: bool,
+        ciphers: (CipherState<C>, CipherState<C>),
+        exporter: Exporter,
+    ) -> Self {
+        let (c1, c2) = ciphers;
+        let (send, recv) = if initiator { (c1, c2) } else { (c2, c1) };
+        Self {
+            send,
+            recv,
+            exporter,
+            initiator,
+        }
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Encrypts `payload` into a message for the peer, fails if the message would be longer than
+    /// 65535 bytes or the sending nonce is exhausted.
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        if payload.len() + C::TAGLEN > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong);
+        }
+        self.send.encrypt_with_ad(&[], payload)
+    }
+
+    /// Decrypts a message from the peer, the receiving nonce is only increased on success.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong);
+        }
+        self.recv.decrypt_with_ad(&[], message)
+    }
+
+    /// The nonce of the next message sent.
+    pub fn sending_nonce(&self) -> u64 {
+        self.send.nonce()
+    }
+
+    /// The nonce of the next message read.
+    pub fn receiving_nonce(&self) -> u64 {
+        self.recv.nonce()
+    }
+
+    /// REKEY of the sending key, the peer has to call [`rekey_recv`](Self::rekey_recv) before
+    /// reading the next message.
+    pub fn rekey_send(&mut self) {
+        self.send.rekey();
+    }
+
+    /// REKEY of the receiving key, matching a [`rekey_send`](Self::rekey_send) of the peer.
+    pub fn rekey_recv(&mut self) {
+        self.recv.rekey();
+    }
+
+    /// Keying material for the application, see [`Exporter`]. Both peers get the same bytes for
+    /// the same `label`, `context` and length.
+    pub fn export_keying_material(&self, label: &[u8], context: &[u8], out: &mut [u8]) {
+        self.exporter.export(label, context, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colloid::cipher::ChaChaPoly;
+
+    fn pair() -> (TransportState<ChaChaPoly>, TransportState<ChaChaPoly>) {
+        let ciphers = || {
+            (
+                CipherState::init(SecretKey::from([1u8; 32])),
+                CipherState::init(SecretKey::from([2u8; 32])),
+            )
+        };
+        let exporter = || Exporter::new(&[0u8; 32], &[0u8; 32]);
+        (
+            TransportState::new(true, ciphers(), exporter()),
+            TransportState::new(false, ciphers(), exporter()),
+        )
+    }
+
+    #[test]
+    fn directions() {
+        let (mut initiator, mut responder) = pair();
+        let message = initiator.write_message(b"one").unwrap();
+        assert!(matches!(
+            initiator.read_message(&message),
+            Err(Error::Decrypt)
+        ));
+        assert_eq!(responder.read_message(&message).unwrap(), b"one");
+        let message = responder.write_message(b"two").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"two");
+        assert_eq!(initiator.sending_nonce(), 1);
+        assert_eq!(initiator.receiving_nonce(), 1);
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let (mut initiator, mut responder) = pair();
+        let payload = [0u8; MAX_MESSAGE_LEN - 16];
+        let message = initiator.write_message(&payload).unwrap();
+        assert_eq!(message.len(), MAX_MESSAGE_LEN);
+        assert_eq!(responder.read_message(&message).unwrap(), payload);
+        assert!(matches!(
+            initiator.write_message(&[0u8; MAX_MESSAGE_LEN - 15]),
+            Err(Error::MessageTooLong)
+        ));
+        assert!(matches!(
+            responder.read_message(&[0u8; MAX_MESSAGE_LEN + 1]),
+            Err(Error::MessageTooLong)
+        ));
+        assert_eq!(initiator.sending_nonce(), 1);
+    }
+
+    #[test]
+    fn nonce_exhaustion() {
+        let (mut initiator, mut responder) = pair();
+        initiator.send.set_nonce(u64::MAX - 1);
+        responder.recv.set_nonce(u64::MAX - 1);
+        let message = initiator.write_message(b"last").unwrap();
+        assert_eq!(responder.read_message(&message).unwrap(), b"last");
+        assert!(matches!(
+            initiator.write_message(b"wrapped"),
+            Err(Error::NonceExhausted)
+        ));
+        assert!(matches!(
+            responder.read_message(&message),
+            Err(Error::NonceExhausted)
+        ));
+        // Rekeying keeps the nonce, only a new handshake gives fresh ones.
+        initiator.rekey_send();
+        assert!(matches!(
+            initiator.write_message(b"wrapped"),
+            Err(Error::NonceExhausted)
+        ));
+    }
+
+    #[test]
+    fn rekey() {
+        let (mut initiator, mut responder) = pair();
+        let before = initiator.write_message(b"hello").unwrap();
+        initiator.rekey_send();
+        let message = initiator.write_message(b"hello").unwrap();
+        assert_eq!(responder.read_message(&before).unwrap(), b"hello");
+        assert!(responder.read_message(&message).is_err());
+        responder.rekey_recv();
+        assert_eq!(responder.read_message(&message).unwrap(), b"hello");
+
+        // The other direction is unaffected.
+        let message = responder.write_message(b"world").unwrap();
+        assert_eq!(initiator.read_message(&message).unwrap(), b"world");
+    }
+
+    #[test]
+    fn exporter_is_domain_separated() {
+        let exporter = Exporter::new(&[1u8; 32], &[2u8; 32]);
+        let export = |label: &[u8], context: &[u8]| {
+            let mut out = [0u8; 32];
+            exporter.export(label, context, &mut out);
+            out
+        };
+        assert_eq!(export(b"label", b"context"), export(b"label", b"context"));
+        assert_ne!(export(b"label", b"context"), export(b"labelc", b"ontext"));
+        assert_ne!(export(b"label", b"context"), export(b"other", b"context"));
+
+        let mut short = [0u8; 16];
+        exporter.export(b"label", b"context", &mut short);
+        assert_ne!(short, export(b"label", b"context")[..16]);
+
+        let other = Exporter::new(&[1u8; 32], &[3u8; 32]);
+        let mut out = [0u8; 32];
+        other.export(b"label", b"context", &mut out);
+        assert_ne!(out, export(b"label", b"context"));
+    }
+}
@@ -0,0 +1,64 @@
+//! Checks colloid and smog without default features, where both crates are `no_std` + `alloc`.
+//!
+//! `builds_without_default_features` runs on the host, so a `std` import slipping into either
+//! crate fails the test suite without an embedded target installed. A `std` dependency only shows
+//! up on a target without `std`: `builds_for_no_std_target` checks against `thumbv7em-none-eabi`
+//! and is ignored by default, run it with `cargo test -p smog --test no_std -- --ignored` after
+//! `rustup target add thumbv7em-none-eabi`.
+
+use std::process::Command;
+
+const TARGET: &str = "thumbv7em-none-eabi";
+
+// Every feature that doesn't need std.
+const FEATURES: &str = "smog/chachapoly,smog/aesgcm,smog/blake2,smog/sha2,smog/hybrid";
+
+// `cargo check` of both crates with `args`, in a separate target directory `dir` since the outer
+// cargo holds the lock on the default one.
+fn check(dir: &str, args: &[&str]) {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    let output = Command::new(env!("CARGO"))
+        .args([
+            "check",
+            "-p",
+            "colloid",
+            "-p",
+            "smog",
+            "--no-default-features",
+        ])
+        .args(args)
+        .env(
+            "CARGO_TARGET_DIR",
+            format!("{manifest_dir}/../target/{dir}"),
+        )
+        .current_dir(manifest_dir)
+        .output()
+        .expect("failed to run cargo");
+    assert!(
+        output.status.success(),
+        "no_std build failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn builds_without_default_features() {
+    check("no-default-features", &[]);
+}
+
+#[test]
+#[ignore = "needs the thumbv7em-none-eabi target"]
+fn builds_for_no_std_target() {
+    let installed = Command::new("rustc")
+        .args(["--print", "target-libdir", "--target", TARGET])
+        .output()
+        .is_ok_and(|output| {
+            output.status.success()
+                && std::path::Path::new(String::from_utf8_lossy(&output.stdout).trim()).is_dir()
+        });
+    assert!(
+        installed,
+        "the {TARGET} target is not installed, run `rustup target add {TARGET}`"
+    );
+    check("no-std", &["--features", FEATURES, "--target", TARGET]);
+}
@@ -0,0 +1,272 @@
+//! Runs the cacophony/snow JSON test vectors of `tests/vectors` through `HandshakeState` and the
+//! transport phase. Every vector is reported as passed, failed or skipped (suites not enabled in
+//! this build), run with `--nocapture` to see the report.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use colloid::cipher::{AesGcm, ChaChaPoly, Cipher as ChaCha12Blake3};
+use colloid::dh::{X448, X25519};
+use colloid::hash::{Blake2b, Blake2s, Blake3, Sha256, Sha512};
+use colloid::{CipherFunction, DhFunction, HashFunction, SecretKey};
+use serde::Deserialize;
+use smog::protocol_name::{Cipher, Dh, Hash, ProtocolName};
+use smog::state_machines::handshake_state::{HandshakeState, Keys, LocalKey, Modifier, RemoteKey};
+
+#[derive(Deserialize)]
+struct Vectors {
+    vectors: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    payload: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+struct Vector {
+    #[serde(alias = "name")]
+    protocol_name: String,
+    #[serde(default)]
+    fail: bool,
+    init_prologue: String,
+    init_psks: Option<Vec<String>>,
+    init_static: Option<String>,
+    init_ephemeral: Option<String>,
+    init_remote_static: Option<String>,
+    resp_prologue: String,
+    resp_psks: Option<Vec<String>>,
+    resp_static: Option<String>,
+    resp_ephemeral: Option<String>,
+    resp_remote_static: Option<String>,
+    handshake_hash: Option<String>,
+    messages: Vec<Message>,
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+fn bytes(hex: &str) -> Result<Vec<u8>, String> {
+    hex::decode(hex).map_err(|e| format!("invalid hex: {e}"))
+}
+
+fn keys<D: DhFunction>(
+    name: &ProtocolName,
+    s: &Option<String>,
+    e: &Option<String>,
+    rs: &Option<String>,
+    psks: &Option<Vec<String>>,
+) -> Result<Keys<D>, String> {
+    let private_key = |hex: &str| {
+        D::private_key_from_bytes(&bytes(hex)?).ok_or_else(|| "invalid private key".to_string())
+    };
+    let mut local_key = LocalKey::default();
+    if let Some(s) = s {
+        local_key.set_s(private_key(s)?);
+    }
+    if let Some(e) = e {
+        local_key.set_e(private_key(e)?);
+    }
+    let mut remote_key = RemoteKey::default();
+    if let Some(rs) = rs {
+        remote_key.set_s(D::public_key_from_bytes(&bytes(rs)?).ok_or("invalid public key")?);
+    }
+    let mut keys = Keys::new(local_key, remote_key);
+    // The PSKs are listed in the order of the modifiers.
+    let indices = name.modifiers.iter().filter_map(|modifier| match modifier {
+        Modifier::Psk(index) => Some(*index),
+        _ => None,
+    });
+    for (index, psk) in indices.zip(psks.iter().flatten()) {
+        let psk: [u8; 32] = bytes(psk)?.try_into().map_err(|_| "PSK is not 32 bytes")?;
+        keys.set_psk(index, SecretKey::from(psk));
+    }
+    Ok(keys)
+}
+
+fn check(i: usize, expected: &Message, ciphertext: &[u8], payload: &[u8]) -> Result<(), String> {
+    if ciphertext != bytes(&expected.ciphertext)? {
+        return Err(format!(
+            "message {i}: expected ciphertext {}, got {}",
+            expected.ciphertext,
+            hex::encode(ciphertext)
+        ));
+    }
+    if payload != bytes(&expected.payload)? {
+        return Err(format!("message {i}: payload mismatch"));
+    }
+    Ok(())
+}
+
+fn run<D: DhFunction, C: CipherFunction, H: HashFunction>(
+    name: &ProtocolName,
+    vector: &Vector,
+) -> Result<(), String> {
+    let pattern = name.handshake_pattern().map_err(|e| e.to_string())?;
+    let one_way = pattern.messages().len() == 1;
+    let init_keys = keys::<D>(
+        name,
+        &vector.init_static,
+        &vector.init_ephemeral,
+        &vector.init_remote_static,
+        &vector.init_psks,
+    )?;
+    let resp_keys = keys::<D>(
+        name,
+        &vector.resp_static,
+        &vector.resp_ephemeral,
+        &vector.resp_remote_static,
+        &vector.resp_psks,
+    )?;
+    let mut initiator = HandshakeState::<D, C, H>::init(
+        pattern.clone(),
+        true,
+        &bytes(&vector.init_prologue)?,
+        init_keys,
+    )
+    .map_err(|e| e.to_string())?;
+    let mut responder =
+        HandshakeState::<D, C, H>::init(pattern, false, &bytes(&vector.resp_prologue)?, resp_keys)
+            .map_err(|e| e.to_string())?;
+
+    let mut messages = vector.messages.iter().enumerate();
+    while !initiator.is_finished() {
+        let (i, expected) = messages.next().ok_or("not enough messages")?;
+        let (writer, reader) = if initiator.is_my_turn() {
+            (&mut initiator, &mut responder)
+        } else {
+            (&mut responder, &mut initiator)
+        };
+        let mut ciphertext = Vec::new();
+        let mut payload = Vec::new();
+        writer
+            .write_message(&bytes(&expected.payload)?, &mut ciphertext)
+            .map_err(|e| format!("message {i}: {e}"))?;
+        reader
+            .read_message(&ciphertext, &mut payload)
+            .map_err(|e| format!("message {i}: {e}"))?;
+        check(i, expected, &ciphertext, &payload)?;
+    }
+    if let Some(handshake_hash) = &vector.handshake_hash {
+        let handshake_hash = bytes(handshake_hash)?;
+        if initiator.get_handshake_hash().as_ref() != handshake_hash
+            || responder.get_handshake_hash().as_ref() != handshake_hash
+        {
+            return Err("handshake hash mismatch".into());
+        }
+    }
+
+    let mut initiator = initiator.into_transport().map_err(|e| e.to_string())?;
+    let mut responder = responder.into_transport().map_err(|e| e.to_string())?;
+    for (i, expected) in messages {
+        let (writer, reader) = if one_way || i % 2 == 0 {
+            (&mut initiator, &mut responder)
+        } else {
+            (&mut responder, &mut initiator)
+        };
+        let ciphertext = writer
+            .write_message(&bytes(&expected.payload)?)
+            .map_err(|e| format!("message {i}: {e}"))?;
+        let payload = reader
+            .read_message(&ciphertext)
+            .map_err(|e| format!("message {i}: {e}"))?;
+        check(i, expected, &ciphertext, &payload)?;
+    }
+    Ok(())
+}
+
+fn run_cipher<D: DhFunction>(name: &ProtocolName, vector: &Vector) -> Result<(), String> {
+    match name.cipher {
+        Cipher::ChaChaPoly => run_hash::<D, ChaChaPoly>(name, vector),
+        Cipher::AesGcm => run_hash::<D, AesGcm>(name, vector),
+        Cipher::ChaCha12Blake3 => run_hash::<D, ChaCha12Blake3>(name, vector),
+    }
+}
+
+fn run_hash<D: DhFunction, C: CipherFunction>(
+    name: &ProtocolName,
+    vector: &Vector,
+) -> Result<(), String> {
+    match name.hash {
+        Hash::Blake2s => run::<D, C, Blake2s>(name, vector),
+        Hash::Blake2b => run::<D, C, Blake2b>(name, vector),
+        Hash::Sha256 => run::<D, C, Sha256>(name, vector),
+        Hash::Sha512 => run::<D, C, Sha512>(name, vector),
+        Hash::Blake3 => run::<D, C, Blake3>(name, vector),
+    }
+}
+
+fn outcome(vector: &Vector) -> Outcome {
+    let name: ProtocolName = match vector.protocol_name.parse() {
+        Ok(name) => name,
+        Err(e) => return Outcome::Skip(e.to_string()),
+    };
+    if name.kem.is_some() {
+        return Outcome::Skip("hybrid vectors are not supported".into());
+    }
+    let result = match name.dh {
+        Dh::X25519 => run_cipher::<X25519>(&name, vector),
+        Dh::X448 => run_cipher::<X448>(&name, vector),
+    };
+    match (result, vector.fail) {
+        (Ok(()), false) | (Err(_), true) => Outcome::Pass,
+        (Ok(()), true) => Outcome::Fail("expected to fail".into()),
+        (Err(e), false) => Outcome::Fail(e),
+    }
+}
+
+#[test]
+fn test_vectors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors");
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+
+    // (passed, failed, skipped) per suite.
+    let mut suites: BTreeMap<String, [usize; 3]> = BTreeMap::new();
+    let mut failed = 0;
+    for file in files {
+        let vectors: Vectors = serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        let file = file.file_name().unwrap().to_string_lossy().into_owned();
+        for vector in vectors.vectors {
+            let suite = vector
+                .protocol_name
+                .splitn(3, '_')
+                .nth(2)
+                .unwrap_or_default()
+                .to_string();
+            let counts = suites.entry(suite).or_default();
+            match outcome(&vector) {
+                Outcome::Pass => {
+                    counts[0] += 1;
+                    println!("PASS {file} {}", vector.protocol_name);
+                }
+                Outcome::Fail(reason) => {
+                    counts[1] += 1;
+                    failed += 1;
+                    println!("FAIL {file} {}: {reason}", vector.protocol_name);
+                }
+                Outcome::Skip(reason) => {
+                    counts[2] += 1;
+                    println!("SKIP {file} {}: {reason}", vector.protocol_name);
+                }
+            }
+        }
+    }
+
+    println!();
+    for (suite, [passed, failed, skipped]) in &suites {
+        println!("{suite}: {passed} passed, {failed} failed, {skipped} skipped");
+    }
+    assert_eq!(failed, 0, "{failed} test vectors failed");
+    assert!(suites.values().any(|[passed, ..]| *passed > 0));
+}
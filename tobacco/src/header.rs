@@ -28,6 +28,9 @@ impl ConnectionId {
     pub fn len(&self) -> usize {
         self.cid.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.cid.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
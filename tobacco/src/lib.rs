@@ -1 +1 @@
-mod header;
+pub mod header;